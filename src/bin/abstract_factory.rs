@@ -16,65 +16,1200 @@
  * - когда вы хотите предоставить библиотеку объектов, раскрывая только их
  *   интерфейсы, но не реализацию
 **/
+use std::{
+    any::TypeId,
+    collections::BTreeMap,
+    env, fmt, fs,
+    hint::black_box,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+};
 
-trait Button {
+/*
+ * Тема — это тип-метка семейства виджетов. Каждый виджет через
+ * ассоциированный тип Theme сообщает, к какому семейству он относится,
+ * а контейнеры принимают только виджеты своей темы. Поэтому положить
+ * ButtonQT в окно GTK нельзя: такой код не скомпилируется
+ */
+trait Theme: 'static {
+    const NAME: &'static str;
+    // Рамка, которой тема обводит окна и кнопки
+    const BORDER: Border;
+}
+struct Qt {}
+impl Theme for Qt {
+    const NAME: &'static str = "qt";
+    const BORDER: Border = Border {
+        top_left: '+',
+        top_right: '+',
+        bottom_left: '+',
+        bottom_right: '+',
+        horizontal: '-',
+        vertical: '|',
+    };
+}
+struct Gtk {}
+impl Theme for Gtk {
+    const NAME: &'static str = "gtk";
+    const BORDER: Border = Border {
+        top_left: '╭',
+        top_right: '╮',
+        bottom_left: '╰',
+        bottom_right: '╯',
+        horizontal: '─',
+        vertical: '│',
+    };
+}
+
+struct Border {
+    top_left: char,
+    top_right: char,
+    bottom_left: char,
+    bottom_right: char,
+    horizontal: char,
+    vertical: char,
+}
+
+/*
+ * Безголовая отрисовка: виджеты рисуются в сетку символов, а не на экран.
+ * Результат зависит только от дерева виджетов, поэтому его можно сравнивать
+ * с сохраненным эталоном в тестах на машинах без дисплея
+ */
+struct Canvas {
+    width: usize,
+    height: usize,
+    cells: Vec<char>,
+}
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Canvas {
+            width,
+            height,
+            cells: vec![' '; width * height],
+        }
+    }
+    // Все, что выходит за границы холста, отбрасывается
+    fn put(&mut self, x: usize, y: usize, ch: char) {
+        if x < self.width && y < self.height {
+            self.cells[y * self.width + x] = ch;
+        }
+    }
+    fn text(&mut self, x: usize, y: usize, text: &str) {
+        for (i, ch) in text.chars().enumerate() {
+            self.put(x + i, y, ch);
+        }
+    }
+    // Переносит на холст готовый блок строк, например отрисовку дочернего виджета
+    fn blit(&mut self, x: usize, y: usize, lines: &[String]) {
+        for (i, line) in lines.iter().enumerate() {
+            self.text(x, y + i, line);
+        }
+    }
+    // Рамка с необязательным заголовком в верхней стороне
+    fn frame(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        border: &Border,
+        title: Option<&str>,
+    ) {
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        for i in x + 1..right {
+            self.put(i, y, border.horizontal);
+            self.put(i, bottom, border.horizontal);
+        }
+        for j in y + 1..bottom {
+            self.put(x, j, border.vertical);
+            self.put(right, j, border.vertical);
+        }
+        self.put(x, y, border.top_left);
+        self.put(right, y, border.top_right);
+        self.put(x, bottom, border.bottom_left);
+        self.put(right, bottom, border.bottom_right);
+        if let Some(title) = title {
+            self.text(x + 2, y, &format!(" {} ", title));
+        }
+    }
+    // Строки без хвостовых пробелов, чтобы эталоны не зависели от редактора
+    fn lines(&self) -> Vec<String> {
+        self.cells
+            .chunks(self.width.max(1))
+            .take(self.height)
+            .map(|row| row.iter().collect::<String>().trim_end().to_string())
+            .collect()
+    }
+}
+
+// Ширина и высота блока строк в символах
+fn block_size(lines: &[String]) -> (usize, usize) {
+    let width = lines.iter().map(|line| line.chars().count()).max();
+    (width.unwrap_or(0), lines.len())
+}
+
+// Текст в рамке темы, так рисуются кнопки
+fn framed<T: Theme>(text: &str) -> Vec<String> {
+    let mut canvas = Canvas::new(text.chars().count() + 4, 3);
+    canvas.frame(0, 0, canvas.width, 3, &T::BORDER, None);
+    canvas.text(2, 1, text);
+    canvas.lines()
+}
+
+// Каждый виджет умеет нарисовать себя в текстовый буфер, по строке на элемент
+trait Widget {
+    type Theme: Theme;
+    fn render(&self) -> Vec<String>;
+}
+
+// Отрисовка целиком одной строкой, в таком виде ее удобно хранить как эталон
+fn render_to_string<W: Widget + ?Sized>(widget: &W) -> String {
+    let mut text = widget.render().join("\n");
+    text.push('\n');
+    text
+}
+
+trait Button: Widget {
     fn click(&self);
 }
-struct ButtonQT {}
+struct ButtonQT {
+    text: String,
+}
+impl Widget for ButtonQT {
+    type Theme = Qt;
+    fn render(&self) -> Vec<String> {
+        framed::<Qt>(&self.text)
+    }
+}
 impl Button for ButtonQT {
     fn click(&self) {
         println!("Clicked QT button");
     }
 }
-struct ButtonGTK {}
+struct ButtonGTK {
+    text: String,
+}
+impl Widget for ButtonGTK {
+    type Theme = Gtk;
+    fn render(&self) -> Vec<String> {
+        framed::<Gtk>(&self.text)
+    }
+}
 impl Button for ButtonGTK {
     fn click(&self) {
         println!("Clicked GTK button");
     }
 }
-trait Label {
+
+trait Label: Widget {
     fn onfocus(&self);
 }
-struct LabelQT {}
+struct LabelQT {
+    text: String,
+}
+impl Widget for LabelQT {
+    type Theme = Qt;
+    fn render(&self) -> Vec<String> {
+        vec![self.text.clone()]
+    }
+}
 impl Label for LabelQT {
     fn onfocus(&self) {
         println!("Onfocus QT label");
     }
 }
-struct LabelGTK {}
+struct LabelGTK {
+    text: String,
+}
+impl Widget for LabelGTK {
+    type Theme = Gtk;
+    fn render(&self) -> Vec<String> {
+        vec![self.text.clone()]
+    }
+}
 impl Label for LabelGTK {
     fn onfocus(&self) {
         println!("Onfocus GTK label");
     }
 }
+
+trait TextInput: Widget {
+    fn set_text(&mut self, text: &str);
+    fn text(&self) -> &str;
+}
+// Поле ввода показывает подсказку, пока текст пустой
+fn input_content(text: &str, placeholder: &str) -> String {
+    let content = if text.is_empty() { placeholder } else { text };
+    format!("{:_<12}", content)
+}
+struct TextInputQT {
+    text: String,
+    placeholder: String,
+}
+impl Widget for TextInputQT {
+    type Theme = Qt;
+    fn render(&self) -> Vec<String> {
+        vec![format!(
+            "[{}]",
+            input_content(&self.text, &self.placeholder)
+        )]
+    }
+}
+impl TextInput for TextInputQT {
+    fn set_text(&mut self, text: &str) {
+        self.text = text.to_string();
+    }
+    fn text(&self) -> &str {
+        &self.text
+    }
+}
+struct TextInputGTK {
+    text: String,
+    placeholder: String,
+}
+impl Widget for TextInputGTK {
+    type Theme = Gtk;
+    fn render(&self) -> Vec<String> {
+        vec![format!(
+            "|{}|",
+            input_content(&self.text, &self.placeholder)
+        )]
+    }
+}
+impl TextInput for TextInputGTK {
+    fn set_text(&mut self, text: &str) {
+        self.text = text.to_string();
+    }
+    fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+trait Checkbox: Widget {
+    fn toggle(&mut self);
+    fn is_checked(&self) -> bool;
+}
+struct CheckboxQT {
+    text: String,
+    checked: bool,
+}
+impl Widget for CheckboxQT {
+    type Theme = Qt;
+    fn render(&self) -> Vec<String> {
+        let mark = if self.checked { 'x' } else { ' ' };
+        vec![format!("[{}] {}", mark, self.text)]
+    }
+}
+impl Checkbox for CheckboxQT {
+    fn toggle(&mut self) {
+        self.checked = !self.checked;
+    }
+    fn is_checked(&self) -> bool {
+        self.checked
+    }
+}
+struct CheckboxGTK {
+    text: String,
+    checked: bool,
+}
+impl Widget for CheckboxGTK {
+    type Theme = Gtk;
+    fn render(&self) -> Vec<String> {
+        let mark = if self.checked { '*' } else { ' ' };
+        vec![format!("({}) {}", mark, self.text)]
+    }
+}
+impl Checkbox for CheckboxGTK {
+    fn toggle(&mut self) {
+        self.checked = !self.checked;
+    }
+    fn is_checked(&self) -> bool {
+        self.checked
+    }
+}
+
+/*
+ * Контейнеры общие для всех тем, но параметризованы темой и принимают только
+ * Box<dyn Widget<Theme = T>>
+ */
+struct Column<T: Theme> {
+    children: Vec<Box<dyn Widget<Theme = T>>>,
+}
+impl<T: Theme> Column<T> {
+    fn new() -> Self {
+        Column { children: vec![] }
+    }
+    fn add(&mut self, child: Box<dyn Widget<Theme = T>>) {
+        self.children.push(child);
+    }
+}
+impl<T: Theme> Widget for Column<T> {
+    type Theme = T;
+    fn render(&self) -> Vec<String> {
+        self.children
+            .iter()
+            .flat_map(|child| child.render())
+            .collect()
+    }
+}
+
+struct Row<T: Theme> {
+    children: Vec<Box<dyn Widget<Theme = T>>>,
+}
+impl<T: Theme> Row<T> {
+    fn new() -> Self {
+        Row { children: vec![] }
+    }
+    fn add(&mut self, child: Box<dyn Widget<Theme = T>>) {
+        self.children.push(child);
+    }
+}
+impl<T: Theme> Widget for Row<T> {
+    type Theme = T;
+    // Дети стоят рядом через пробел и выровнены по верхнему краю
+    fn render(&self) -> Vec<String> {
+        let blocks: Vec<Vec<String>> =
+            self.children.iter().map(|child| child.render()).collect();
+        let sizes: Vec<_> =
+            blocks.iter().map(|block| block_size(block)).collect();
+        let width = sizes.iter().map(|(w, _)| w + 1).sum::<usize>();
+        let height = sizes.iter().map(|(_, h)| *h).max().unwrap_or(0);
+        let mut canvas = Canvas::new(width.saturating_sub(1), height);
+        let mut x = 0;
+        for (block, (w, _)) in blocks.iter().zip(sizes) {
+            canvas.blit(x, 0, block);
+            x += w + 1;
+        }
+        canvas.lines()
+    }
+}
+
+struct Window<T: Theme> {
+    title: String,
+    content: Column<T>,
+    theme: PhantomData<T>,
+}
+impl<T: Theme> Window<T> {
+    fn new(title: &str) -> Self {
+        Window {
+            title: title.to_string(),
+            content: Column::new(),
+            theme: PhantomData,
+        }
+    }
+    fn add(&mut self, child: Box<dyn Widget<Theme = T>>) {
+        self.content.add(child);
+    }
+}
+impl<T: Theme> Widget for Window<T> {
+    type Theme = T;
+    // Рамка темы с заголовком и отступом в один символ вокруг содержимого
+    fn render(&self) -> Vec<String> {
+        let content = self.content.render();
+        let (width, height) = block_size(&content);
+        let width = (width + 4).max(self.title.chars().count() + 6);
+        let mut canvas = Canvas::new(width, height + 2);
+        canvas.frame(0, 0, width, height + 2, &T::BORDER, Some(&self.title));
+        canvas.blit(2, 1, &content);
+        canvas.lines()
+    }
+}
+
 trait WidgetFactory {
+    type Theme: Theme;
     // По сути фабричные методы из паттерна "фабричный метод"
-    fn create_button(&self) -> Box<dyn Button>;
-    fn create_label(&self) -> Box<dyn Label>;
+    fn create_button(&self, text: &str)
+        -> Box<dyn Button<Theme = Self::Theme>>;
+    fn create_label(&self, text: &str) -> Box<dyn Label<Theme = Self::Theme>>;
+    fn create_text_input(
+        &self,
+        placeholder: &str,
+    ) -> Box<dyn TextInput<Theme = Self::Theme>>;
+    fn create_checkbox(
+        &self,
+        text: &str,
+    ) -> Box<dyn Checkbox<Theme = Self::Theme>>;
+    // Контейнеры одинаковы для всех тем, их создание общее
+    fn create_window(&self, title: &str) -> Window<Self::Theme> {
+        Window::new(title)
+    }
+    fn create_column(&self) -> Column<Self::Theme> {
+        Column::new()
+    }
+    fn create_row(&self) -> Row<Self::Theme> {
+        Row::new()
+    }
 }
 struct QtWidgetFactory {}
 impl WidgetFactory for QtWidgetFactory {
-    fn create_button(&self) -> Box<dyn Button> {
-        Box::new(ButtonQT {})
+    type Theme = Qt;
+    fn create_button(&self, text: &str) -> Box<dyn Button<Theme = Qt>> {
+        Box::new(ButtonQT {
+            text: text.to_string(),
+        })
+    }
+    fn create_label(&self, text: &str) -> Box<dyn Label<Theme = Qt>> {
+        Box::new(LabelQT {
+            text: text.to_string(),
+        })
+    }
+    fn create_text_input(
+        &self,
+        placeholder: &str,
+    ) -> Box<dyn TextInput<Theme = Qt>> {
+        Box::new(TextInputQT {
+            text: String::new(),
+            placeholder: placeholder.to_string(),
+        })
     }
-    fn create_label(&self) -> Box<dyn Label> {
-        Box::new(LabelQT {})
+    fn create_checkbox(&self, text: &str) -> Box<dyn Checkbox<Theme = Qt>> {
+        Box::new(CheckboxQT {
+            text: text.to_string(),
+            checked: false,
+        })
     }
 }
 struct GtkWidgetFactory {}
 impl WidgetFactory for GtkWidgetFactory {
-    fn create_button(&self) -> Box<dyn Button> {
-        Box::new(ButtonGTK {})
+    type Theme = Gtk;
+    fn create_button(&self, text: &str) -> Box<dyn Button<Theme = Gtk>> {
+        Box::new(ButtonGTK {
+            text: text.to_string(),
+        })
+    }
+    fn create_label(&self, text: &str) -> Box<dyn Label<Theme = Gtk>> {
+        Box::new(LabelGTK {
+            text: text.to_string(),
+        })
+    }
+    fn create_text_input(
+        &self,
+        placeholder: &str,
+    ) -> Box<dyn TextInput<Theme = Gtk>> {
+        Box::new(TextInputGTK {
+            text: String::new(),
+            placeholder: placeholder.to_string(),
+        })
+    }
+    fn create_checkbox(&self, text: &str) -> Box<dyn Checkbox<Theme = Gtk>> {
+        Box::new(CheckboxGTK {
+            text: text.to_string(),
+            checked: false,
+        })
+    }
+}
+
+/*
+ * Параллельная форма фабрики со статической диспетчеризацией. Виджеты
+ * возвращаются по значению, без Box и таблицы виртуальных методов, а код,
+ * написанный против этого трейта, мономорфизируется под каждую тему.
+ * Подходит для горячих путей, где создается много короткоживущих виджетов.
+ * В контейнер такой виджет все равно кладется через Box::new
+ */
+trait StaticWidgetFactory {
+    type Theme: Theme;
+    type Button: Button<Theme = Self::Theme>;
+    type Label: Label<Theme = Self::Theme>;
+    type TextInput: TextInput<Theme = Self::Theme>;
+    type Checkbox: Checkbox<Theme = Self::Theme>;
+    fn button(&self, text: &str) -> Self::Button;
+    fn label(&self, text: &str) -> Self::Label;
+    fn text_input(&self, placeholder: &str) -> Self::TextInput;
+    fn checkbox(&self, text: &str) -> Self::Checkbox;
+}
+impl StaticWidgetFactory for QtWidgetFactory {
+    type Theme = Qt;
+    type Button = ButtonQT;
+    type Label = LabelQT;
+    type TextInput = TextInputQT;
+    type Checkbox = CheckboxQT;
+    fn button(&self, text: &str) -> ButtonQT {
+        ButtonQT {
+            text: text.to_string(),
+        }
+    }
+    fn label(&self, text: &str) -> LabelQT {
+        LabelQT {
+            text: text.to_string(),
+        }
+    }
+    fn text_input(&self, placeholder: &str) -> TextInputQT {
+        TextInputQT {
+            text: String::new(),
+            placeholder: placeholder.to_string(),
+        }
+    }
+    fn checkbox(&self, text: &str) -> CheckboxQT {
+        CheckboxQT {
+            text: text.to_string(),
+            checked: false,
+        }
+    }
+}
+impl StaticWidgetFactory for GtkWidgetFactory {
+    type Theme = Gtk;
+    type Button = ButtonGTK;
+    type Label = LabelGTK;
+    type TextInput = TextInputGTK;
+    type Checkbox = CheckboxGTK;
+    fn button(&self, text: &str) -> ButtonGTK {
+        ButtonGTK {
+            text: text.to_string(),
+        }
+    }
+    fn label(&self, text: &str) -> LabelGTK {
+        LabelGTK {
+            text: text.to_string(),
+        }
+    }
+    fn text_input(&self, placeholder: &str) -> TextInputGTK {
+        TextInputGTK {
+            text: String::new(),
+            placeholder: placeholder.to_string(),
+        }
+    }
+    fn checkbox(&self, text: &str) -> CheckboxGTK {
+        CheckboxGTK {
+            text: text.to_string(),
+            checked: false,
+        }
+    }
+}
+
+/*
+ * Одна и та же нагрузка для двух форм фабрики: много короткоживущих полей
+ * ввода и флажков. Возвращается сумма, чтобы компилятор не выбросил работу
+ */
+fn churn_boxed<F: WidgetFactory>(factory: &F, count: usize) -> usize {
+    let mut total = 0;
+    for i in 0..count {
+        let mut input = factory.create_text_input("");
+        input.set_text("x");
+        let mut checkbox = factory.create_checkbox("");
+        if i % 2 == 0 {
+            checkbox.toggle();
+        }
+        total += input.text().len() + checkbox.is_checked() as usize;
+    }
+    total
+}
+fn churn_static<F: StaticWidgetFactory>(factory: &F, count: usize) -> usize {
+    let mut total = 0;
+    for i in 0..count {
+        let mut input = factory.text_input("");
+        input.set_text("x");
+        let mut checkbox = factory.checkbox("");
+        if i % 2 == 0 {
+            checkbox.toggle();
+        }
+        total += input.text().len() + checkbox.is_checked() as usize;
+    }
+    total
+}
+
+fn bench(name: &str, f: impl FnOnce() -> usize) {
+    let start = Instant::now();
+    let total = black_box(f());
+    println!("{}: {} in {:?}", name, total, start.elapsed());
+}
+
+/*
+ * Стертая форма фабрики для выбора темы во время выполнения. Тема здесь
+ * уже не параметр типа, поэтому контейнеры проверяют ее при добавлении
+ * виджета и возвращают ошибку, а не отказываются компилироваться.
+ * Сравнивается TypeId темы, а не имя: чужая тема с именем "qt" за Qt
+ * не сойдет. Имя нужно только для сообщений.
+ * Реализуется автоматически для любой WidgetFactory
+ */
+trait DynWidget {
+    fn theme(&self) -> &'static str;
+    fn theme_id(&self) -> TypeId;
+    fn render(&self) -> Vec<String>;
+}
+trait DynButton: DynWidget {
+    fn click(&self);
+}
+trait DynLabel: DynWidget {
+    fn onfocus(&self);
+}
+trait DynTextInput: DynWidget {
+    fn set_text(&mut self, text: &str);
+    fn text(&self) -> &str;
+}
+trait DynCheckbox: DynWidget {
+    fn toggle(&mut self);
+    fn is_checked(&self) -> bool;
+}
+
+// Обертка, через которую типизированный виджет выступает в стертой форме
+struct Erased<W: ?Sized>(Box<W>);
+impl<W: Widget + ?Sized> DynWidget for Erased<W> {
+    fn theme(&self) -> &'static str {
+        <W::Theme as Theme>::NAME
+    }
+    fn theme_id(&self) -> TypeId {
+        TypeId::of::<W::Theme>()
+    }
+    fn render(&self) -> Vec<String> {
+        self.0.render()
+    }
+}
+impl<B: Button + ?Sized> DynButton for Erased<B> {
+    fn click(&self) {
+        self.0.click()
+    }
+}
+impl<L: Label + ?Sized> DynLabel for Erased<L> {
+    fn onfocus(&self) {
+        self.0.onfocus()
+    }
+}
+impl<I: TextInput + ?Sized> DynTextInput for Erased<I> {
+    fn set_text(&mut self, text: &str) {
+        self.0.set_text(text)
+    }
+    fn text(&self) -> &str {
+        self.0.text()
+    }
+}
+impl<C: Checkbox + ?Sized> DynCheckbox for Erased<C> {
+    fn toggle(&mut self) {
+        self.0.toggle()
+    }
+    fn is_checked(&self) -> bool {
+        self.0.is_checked()
+    }
+}
+
+#[derive(Debug)]
+struct MixedThemes {
+    expected: &'static str,
+    found: &'static str,
+}
+impl fmt::Display for MixedThemes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} widget added to a {} container",
+            self.found, self.expected
+        )
+    }
+}
+
+// Стертый виджет, тема которого проверена при добавлении в контейнер
+struct Checked<T> {
+    widget: Box<dyn DynWidget>,
+    theme: PhantomData<T>,
+}
+impl<T: Theme> Widget for Checked<T> {
+    type Theme = T;
+    fn render(&self) -> Vec<String> {
+        self.widget.render()
+    }
+}
+fn check_theme<T: Theme>(
+    widget: Box<dyn DynWidget>,
+) -> Result<Box<dyn Widget<Theme = T>>, MixedThemes> {
+    if widget.theme_id() != TypeId::of::<T>() {
+        return Err(MixedThemes {
+            expected: T::NAME,
+            found: widget.theme(),
+        });
+    }
+    Ok(Box::new(Checked {
+        widget,
+        theme: PhantomData,
+    }))
+}
+
+trait DynContainer: DynWidget {
+    fn add_widget(
+        &mut self,
+        child: Box<dyn DynWidget>,
+    ) -> Result<(), MixedThemes>;
+}
+impl<T: Theme> DynContainer for Erased<Window<T>> {
+    fn add_widget(
+        &mut self,
+        child: Box<dyn DynWidget>,
+    ) -> Result<(), MixedThemes> {
+        self.0.add(check_theme(child)?);
+        Ok(())
+    }
+}
+impl<T: Theme> DynContainer for Erased<Column<T>> {
+    fn add_widget(
+        &mut self,
+        child: Box<dyn DynWidget>,
+    ) -> Result<(), MixedThemes> {
+        self.0.add(check_theme(child)?);
+        Ok(())
+    }
+}
+impl<T: Theme> DynContainer for Erased<Row<T>> {
+    fn add_widget(
+        &mut self,
+        child: Box<dyn DynWidget>,
+    ) -> Result<(), MixedThemes> {
+        self.0.add(check_theme(child)?);
+        Ok(())
+    }
+}
+
+// Методы названы иначе, чем в WidgetFactory, чтобы вызовы не были неоднозначны
+trait DynWidgetFactory {
+    fn theme(&self) -> &'static str;
+    fn make_button(&self, text: &str) -> Box<dyn DynButton>;
+    fn make_label(&self, text: &str) -> Box<dyn DynLabel>;
+    fn make_text_input(&self, placeholder: &str) -> Box<dyn DynTextInput>;
+    fn make_checkbox(&self, text: &str) -> Box<dyn DynCheckbox>;
+    fn make_window(&self, title: &str) -> Box<dyn DynContainer>;
+    fn make_column(&self) -> Box<dyn DynContainer>;
+    fn make_row(&self) -> Box<dyn DynContainer>;
+}
+impl<F: WidgetFactory> DynWidgetFactory for F {
+    fn theme(&self) -> &'static str {
+        <F::Theme as Theme>::NAME
+    }
+    fn make_button(&self, text: &str) -> Box<dyn DynButton> {
+        Box::new(Erased(self.create_button(text)))
+    }
+    fn make_label(&self, text: &str) -> Box<dyn DynLabel> {
+        Box::new(Erased(self.create_label(text)))
+    }
+    fn make_text_input(&self, placeholder: &str) -> Box<dyn DynTextInput> {
+        Box::new(Erased(self.create_text_input(placeholder)))
+    }
+    fn make_checkbox(&self, text: &str) -> Box<dyn DynCheckbox> {
+        Box::new(Erased(self.create_checkbox(text)))
+    }
+    fn make_window(&self, title: &str) -> Box<dyn DynContainer> {
+        Box::new(Erased(Box::new(self.create_window(title))))
+    }
+    fn make_column(&self) -> Box<dyn DynContainer> {
+        Box::new(Erased(Box::new(self.create_column())))
+    }
+    fn make_row(&self) -> Box<dyn DynContainer> {
+        Box::new(Erased(Box::new(self.create_row())))
+    }
+}
+
+/*
+ * Реестр семейств виджетов: тема выбирается по имени во время выполнения.
+ * Сторонний код может добавить свое семейство через register, не меняя
+ * этот файл
+ */
+type FactoryConstructor = Box<dyn Fn() -> Box<dyn DynWidgetFactory>>;
+
+struct FactoryRegistry {
+    factories: BTreeMap<String, FactoryConstructor>,
+}
+impl FactoryRegistry {
+    fn new() -> Self {
+        FactoryRegistry {
+            factories: BTreeMap::new(),
+        }
+    }
+    // Реестр со встроенными темами
+    fn with_builtin() -> Self {
+        let mut registry = FactoryRegistry::new();
+        registry.register("qt", || Box::new(QtWidgetFactory {}));
+        registry.register("gtk", || Box::new(GtkWidgetFactory {}));
+        registry
+    }
+    fn register(
+        &mut self,
+        name: &str,
+        constructor: impl Fn() -> Box<dyn DynWidgetFactory> + 'static,
+    ) {
+        self.factories
+            .insert(name.to_lowercase(), Box::new(constructor));
+    }
+    fn create(
+        &self,
+        name: &str,
+    ) -> Result<Box<dyn DynWidgetFactory>, ThemeError> {
+        match self.factories.get(&name.to_lowercase()) {
+            Some(constructor) => Ok(constructor()),
+            None => Err(ThemeError {
+                requested: name.to_string(),
+                known: self.names().map(String::from).collect(),
+            }),
+        }
+    }
+    fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.factories.keys().map(String::as_str)
+    }
+    // Тема из переменной окружения, если она не задана, то default
+    fn create_from_env(
+        &self,
+        var: &str,
+        default: &str,
+    ) -> Result<Box<dyn DynWidgetFactory>, ThemeError> {
+        let name = env::var(var).unwrap_or_else(|_| default.to_string());
+        self.create(&name)
+    }
+}
+
+#[derive(Debug)]
+struct ThemeError {
+    requested: String,
+    known: Vec<String>,
+}
+impl fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown theme '{}', known themes: {}",
+            self.requested,
+            self.known.join(", ")
+        )
+    }
+}
+
+// Семейство виджетов, которое добавляет сторонний код
+struct Tui {}
+impl Theme for Tui {
+    const NAME: &'static str = "tui";
+    const BORDER: Border = Border {
+        top_left: '#',
+        top_right: '#',
+        bottom_left: '#',
+        bottom_right: '#',
+        horizontal: '=',
+        vertical: ':',
+    };
+}
+struct ButtonTui {
+    text: String,
+}
+impl Widget for ButtonTui {
+    type Theme = Tui;
+    fn render(&self) -> Vec<String> {
+        vec![format!("<{}>", self.text)]
+    }
+}
+impl Button for ButtonTui {
+    fn click(&self) {
+        println!("Clicked TUI button");
+    }
+}
+struct LabelTui {
+    text: String,
+}
+impl Widget for LabelTui {
+    type Theme = Tui;
+    fn render(&self) -> Vec<String> {
+        vec![self.text.clone()]
+    }
+}
+impl Label for LabelTui {
+    fn onfocus(&self) {
+        println!("Onfocus TUI label");
     }
-    fn create_label(&self) -> Box<dyn Label> {
-        Box::new(LabelGTK {})
+}
+struct TextInputTui {
+    text: String,
+}
+impl Widget for TextInputTui {
+    type Theme = Tui;
+    fn render(&self) -> Vec<String> {
+        vec![format!("> {}", self.text)]
     }
 }
+impl TextInput for TextInputTui {
+    fn set_text(&mut self, text: &str) {
+        self.text = text.to_string();
+    }
+    fn text(&self) -> &str {
+        &self.text
+    }
+}
+struct CheckboxTui {
+    text: String,
+    checked: bool,
+}
+impl Widget for CheckboxTui {
+    type Theme = Tui;
+    fn render(&self) -> Vec<String> {
+        let mark = if self.checked { "on" } else { "off" };
+        vec![format!("{}: {}", self.text, mark)]
+    }
+}
+impl Checkbox for CheckboxTui {
+    fn toggle(&mut self) {
+        self.checked = !self.checked;
+    }
+    fn is_checked(&self) -> bool {
+        self.checked
+    }
+}
+struct TuiWidgetFactory {}
+impl WidgetFactory for TuiWidgetFactory {
+    type Theme = Tui;
+    fn create_button(&self, text: &str) -> Box<dyn Button<Theme = Tui>> {
+        Box::new(ButtonTui {
+            text: text.to_string(),
+        })
+    }
+    fn create_label(&self, text: &str) -> Box<dyn Label<Theme = Tui>> {
+        Box::new(LabelTui {
+            text: text.to_string(),
+        })
+    }
+    fn create_text_input(&self, _: &str) -> Box<dyn TextInput<Theme = Tui>> {
+        Box::new(TextInputTui {
+            text: String::new(),
+        })
+    }
+    fn create_checkbox(&self, text: &str) -> Box<dyn Checkbox<Theme = Tui>> {
+        Box::new(CheckboxTui {
+            text: text.to_string(),
+            checked: false,
+        })
+    }
+}
+
+// Форма входа, одна и та же для любой темы
+struct LoginForm {
+    user: String,
+}
+impl LoginForm {
+    /*
+     * Сборка через типизированную фабрику: код компилируется для каждой
+     * темы отдельно, и смешать виджеты разных тем в нем нельзя
+     */
+    fn build<F: WidgetFactory>(&self, factory: &F) -> Window<F::Theme> {
+        let mut window = factory.create_window("Login");
+        window.add(factory.create_label("Please sign in"));
+        let mut fields = factory.create_column();
+        let mut user = factory.create_text_input("user");
+        user.set_text(&self.user);
+        fields.add(user);
+        fields.add(factory.create_text_input("password"));
+        let mut remember = factory.create_checkbox("Remember me");
+        remember.toggle();
+        fields.add(remember);
+        window.add(Box::new(fields));
+        let mut buttons = factory.create_row();
+        buttons.add(factory.create_button("OK"));
+        buttons.add(factory.create_button("Cancel"));
+        window.add(Box::new(buttons));
+        /*
+         * Кнопка другой темы в окно не попадет, это ошибка компиляции:
+         * window.add(QtWidgetFactory {}.create_button("Help"));
+         * expected `dyn Widget<Theme = F::Theme>`, found `dyn Button<Theme = Qt>`
+         */
+        window
+    }
+    // Та же форма через фабрику из реестра, темы сверяются при добавлении
+    fn build_dyn(
+        &self,
+        factory: &dyn DynWidgetFactory,
+    ) -> Result<Box<dyn DynContainer>, MixedThemes> {
+        let mut window = factory.make_window("Login");
+        let label = factory.make_label("Please sign in");
+        label.onfocus();
+        window.add_widget(label)?;
+        let mut fields = factory.make_column();
+        let mut user = factory.make_text_input("user");
+        user.set_text(&self.user);
+        println!("User: {}", user.text());
+        fields.add_widget(user)?;
+        fields.add_widget(factory.make_text_input("password"))?;
+        let mut remember = factory.make_checkbox("Remember me");
+        remember.toggle();
+        println!("Remember: {}", remember.is_checked());
+        fields.add_widget(remember)?;
+        window.add_widget(fields)?;
+        let mut buttons = factory.make_row();
+        let ok = factory.make_button("OK");
+        ok.click();
+        buttons.add_widget(ok)?;
+        buttons.add_widget(factory.make_button("Cancel"))?;
+        window.add_widget(buttons)?;
+        Ok(window)
+    }
+}
+
+struct Snapshot {
+    name: String,
+    text: String,
+}
+impl Snapshot {
+    // Отрисовка формы входа под именем, по которому хранится ее эталон
+    fn login(theme: &str, lines: Vec<String>) -> Self {
+        let mut text = lines.join("\n");
+        text.push('\n');
+        Snapshot {
+            name: format!("login_{}", theme),
+            text,
+        }
+    }
+    /*
+     * Сверяет отрисовку с эталоном data/snapshots/<name>.txt. С переменной
+     * окружения UPDATE_SNAPSHOTS эталон перезаписывается текущей отрисовкой
+     */
+    fn check(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(format!("{}.txt", self.name));
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::create_dir_all(dir)
+                .and_then(|_| fs::write(&path, &self.text))
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            return Ok(());
+        }
+        let expected = fs::read_to_string(&path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let mismatch = expected
+            .lines()
+            .zip(self.text.lines())
+            .position(|(expected, actual)| expected != actual);
+        match mismatch {
+            Some(line) => {
+                Err(format!("{} differs at line {}", self.name, line + 1))
+            }
+            None if expected.lines().count() != self.text.lines().count() => {
+                Err(format!("{} differs in line count", self.name))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// Все темы приложения: встроенные и сторонняя
+fn registry() -> FactoryRegistry {
+    let mut registry = FactoryRegistry::with_builtin();
+    registry.register("tui", || Box::new(TuiWidgetFactory {}));
+    registry
+}
+
+fn snapshots_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("data/snapshots")
+}
+
 fn main() {
-    // Мы выбираем UI GTK и все элементы создаются в этом стиле
-    let factory = GtkWidgetFactory {};
-    let button = factory.create_button();
-    let label = factory.create_label();
-    button.click();
-    label.onfocus();
+    // Тему выбирает конфигурация: WIDGET_THEME=qt cargo run --bin abstract_factory
+    let registry = registry();
+    let form = LoginForm {
+        user: "admin".to_string(),
+    };
+    let factory = match registry.create_from_env("WIDGET_THEME", "gtk") {
+        Ok(factory) => factory,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    let window = match form.build_dyn(factory.as_ref()) {
+        Ok(window) => window,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    let snapshot = Snapshot::login(factory.theme(), window.render());
+    print!("{}", snapshot.text);
+    let dir = snapshots_dir();
+    if let Err(err) = snapshot.check(&dir) {
+        println!("Snapshot mismatch: {}", err);
+        process::exit(1);
+    }
+    println!("Snapshot {}: ok", snapshot.name);
+    // В стертой форме виджет чужой темы отвергается во время выполнения
+    let mut row = factory.make_row();
+    let foreign = QtWidgetFactory {}.make_button("Help");
+    if let Err(err) = row.add_widget(foreign) {
+        println!("Error: {}", err);
+    }
+    // Типизированная сборка той же формы дает ту же отрисовку
+    let typed = form.build(&GtkWidgetFactory {});
+    let typed = Snapshot::login("gtk", typed.render());
+    println!(
+        "Typed GTK form matches snapshot: {}",
+        typed.check(&dir).is_ok()
+    );
+
+    // Статическая фабрика годится и для дерева виджетов
+    let factory = QtWidgetFactory {};
+    let mut row = Row::<Qt>::new();
+    row.add(Box::new(factory.label("Static")));
+    row.add(Box::new(factory.button("OK")));
+    print!("{}", render_to_string(&row));
+
+    /*
+     * Сравнение форм фабрики запускается только по запросу, цифры имеют
+     * смысл в release-сборке:
+     * WIDGET_BENCH=1 cargo run --release --bin abstract_factory
+     */
+    if env::var_os("WIDGET_BENCH").is_none() {
+        return;
+    }
+    if cfg!(debug_assertions) {
+        println!("Debug build: timings below are not representative");
+    }
+    let count = black_box(1_000_000);
+    let factory = black_box(factory);
+    bench("Boxed widgets", || churn_boxed(&factory, count));
+    bench("Static widgets", || churn_static(&factory, count));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Отрисовка формы каждой зарегистрированной темой совпадает с эталоном
+    #[test]
+    fn login_form_matches_snapshots() {
+        let registry = registry();
+        let form = LoginForm {
+            user: "admin".to_string(),
+        };
+        for name in registry.names() {
+            let factory = registry.create(name).unwrap();
+            let window = form.build_dyn(factory.as_ref()).unwrap();
+            let snapshot = Snapshot::login(name, window.render());
+            if let Err(err) = snapshot.check(&snapshots_dir()) {
+                panic!("{}", err);
+            }
+        }
+    }
+
+    // Чужая тема с тем же именем не проходит проверку контейнера
+    struct FakeQt {}
+    impl Theme for FakeQt {
+        const NAME: &'static str = "qt";
+        const BORDER: Border = Qt::BORDER;
+    }
+    struct FakeButton {}
+    impl Widget for FakeButton {
+        type Theme = FakeQt;
+        fn render(&self) -> Vec<String> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn container_rejects_theme_with_same_name() {
+        let mut row = QtWidgetFactory {}.make_row();
+        let fake: Box<dyn DynWidget> =
+            Box::new(Erased(Box::new(FakeButton {})));
+        assert!(row.add_widget(fake).is_err());
+        assert!(row.add_widget(QtWidgetFactory {}.make_button("OK")).is_ok());
+    }
+
+    // Типизированная сборка рисует то же, что и сборка через реестр
+    #[test]
+    fn typed_form_matches_snapshots() {
+        let form = LoginForm {
+            user: "admin".to_string(),
+        };
+        let qt =
+            Snapshot::login("qt", form.build(&QtWidgetFactory {}).render());
+        let gtk =
+            Snapshot::login("gtk", form.build(&GtkWidgetFactory {}).render());
+        qt.check(&snapshots_dir()).unwrap();
+        gtk.check(&snapshots_dir()).unwrap();
+    }
 }
@@ -13,20 +13,219 @@
  * который их использует
  * - Когда нельзя расширить обязанности объекта с помощью наследования
 **/
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Currency {
+    Rub,
+    Usd,
+}
+
+impl Currency {
+    fn code(&self) -> &'static str {
+        match self {
+            Currency::Rub => "RUB",
+            Currency::Usd => "USD",
+        }
+    }
+}
+
+/*
+ * Деньги храним в копейках (центах) целым числом, чтобы при наложении
+ * декораторов не накапливалась ошибка округления, как с f32
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Money {
+    cents: i64,
+    currency: Currency,
+}
+
+impl Money {
+    fn new(units: i64, cents: i64, currency: Currency) -> Self {
+        Money {
+            cents: units * 100 + cents,
+            currency,
+        }
+    }
+    // Процент задается в базисных пунктах: 1000 = 10%
+    fn percent(self, basis_points: i64, rounding: Rounding) -> Money {
+        Money {
+            cents: rounding.div(self.cents * basis_points, 10_000),
+            currency: self.currency,
+        }
+    }
+    /*
+     * Сложение и вычитание разных валют это ошибка в данных, а не повод
+     * ронять расчет цены, поэтому вместо операторов checked-методы
+     */
+    fn checked_add(self, other: Money) -> Result<Money, CurrencyMismatch> {
+        self.same_currency(other)?;
+        Ok(Money {
+            cents: self.cents + other.cents,
+            currency: self.currency,
+        })
+    }
+    fn checked_sub(self, other: Money) -> Result<Money, CurrencyMismatch> {
+        self.same_currency(other)?;
+        Ok(Money {
+            cents: self.cents - other.cents,
+            currency: self.currency,
+        })
+    }
+    fn same_currency(&self, other: Money) -> Result<(), CurrencyMismatch> {
+        if self.currency != other.currency {
+            return Err(CurrencyMismatch {
+                left: self.currency,
+                right: other.currency,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CurrencyMismatch {
+    left: Currency,
+    right: Currency,
+}
+
+impl fmt::Display for CurrencyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "currency mismatch: {} and {}",
+            self.left.code(),
+            self.right.code()
+        )
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.cents < 0 { "-" } else { "" };
+        let cents = self.cents.abs();
+        write!(
+            f,
+            "{}{}.{:02} {}",
+            sign,
+            cents / 100,
+            cents % 100,
+            self.currency.code()
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Rounding {
+    // В сторону минус бесконечности
+    Floor,
+    // В сторону плюс бесконечности
+    Ceil,
+    // Половина округляется от нуля
+    HalfUp,
+    // Банковское округление: половина к ближайшему четному
+    HalfEven,
+}
+
+impl Rounding {
+    fn div(&self, num: i64, den: i64) -> i64 {
+        let quot = num.div_euclid(den);
+        let rem = num.rem_euclid(den);
+        let round_up = match self {
+            Rounding::Floor => false,
+            Rounding::Ceil => rem > 0,
+            Rounding::HalfUp => 2 * rem > den || (2 * rem == den && num >= 0),
+            Rounding::HalfEven => {
+                2 * rem > den || (2 * rem == den && quot % 2 != 0)
+            }
+        };
+        if round_up {
+            quot + 1
+        } else {
+            quot
+        }
+    }
+}
+
+// Надбавка, которую декоратор добавляет к цене
+#[derive(Clone, Copy)]
+enum Surcharge {
+    Fixed(Money),
+    Percent {
+        basis_points: i64,
+        rounding: Rounding,
+    },
+}
+
+impl Surcharge {
+    fn apply(&self, price: Money) -> Result<Money, CurrencyMismatch> {
+        match *self {
+            Surcharge::Fixed(amount) => price.checked_add(amount),
+            Surcharge::Percent {
+                basis_points,
+                rounding,
+            } => price.checked_add(price.percent(basis_points, rounding)),
+        }
+    }
+}
+
+// Один примененный слой (добавка) и сколько он добавил к цене
+#[derive(Debug)]
+struct Layer {
+    name: String,
+    price: Money,
+}
 
 trait Coffe {
-    fn cost(&self) -> f32;
+    fn cost(&self) -> Result<Money, CurrencyMismatch>;
     fn description(&self) -> String;
+    // Слои в порядке их применения, от внутреннего к внешнему
+    fn layers(&self) -> Result<Vec<Layer>, CurrencyMismatch>;
+    // Снимает ближайший снаружи слой с указанным именем
+    fn remove_layer(self: Box<Self>, name: &str) -> Box<dyn Coffe>;
 }
 
 struct SimpleCoffe {}
 impl Coffe for SimpleCoffe {
-    fn cost(&self) -> f32 {
-        100_f32
+    fn cost(&self) -> Result<Money, CurrencyMismatch> {
+        Ok(Money::new(100, 0, Currency::Rub))
     }
     fn description(&self) -> String {
         "Coffe".to_string()
     }
+    fn layers(&self) -> Result<Vec<Layer>, CurrencyMismatch> {
+        Ok(vec![])
+    }
+    fn remove_layer(self: Box<Self>, _name: &str) -> Box<dyn Coffe> {
+        self
+    }
+}
+
+/*
+ * Каждый декоратор добавляет себя в конец списка слоев, поэтому порядок
+ * в description() и layers() всегда совпадает с порядком обертывания
+ */
+fn push_layer(
+    coffe: &dyn Coffe,
+    inner: &dyn Coffe,
+    name: &str,
+) -> Result<Vec<Layer>, CurrencyMismatch> {
+    let mut layers = inner.layers()?;
+    layers.push(Layer {
+        name: name.to_string(),
+        price: coffe.cost()?.checked_sub(inner.cost()?)?,
+    });
+    Ok(layers)
 }
 
 struct MilkDecoratorCoffe {
@@ -40,19 +239,490 @@ impl MilkDecoratorCoffe {
 }
 
 impl Coffe for MilkDecoratorCoffe {
-    fn cost(&self) -> f32 {
-        self.coffe.cost() * 1.1
+    fn cost(&self) -> Result<Money, CurrencyMismatch> {
+        Surcharge::Percent {
+            basis_points: 1000,
+            rounding: Rounding::HalfUp,
+        }
+        .apply(self.coffe.cost()?)
     }
     fn description(&self) -> String {
         format!("{} with milk", self.coffe.description())
     }
+    fn layers(&self) -> Result<Vec<Layer>, CurrencyMismatch> {
+        push_layer(self, self.coffe.as_ref(), "milk")
+    }
+    fn remove_layer(mut self: Box<Self>, name: &str) -> Box<dyn Coffe> {
+        if name == "milk" {
+            return self.coffe;
+        }
+        self.coffe = self.coffe.remove_layer(name);
+        self
+    }
+}
+
+struct SugarDecoratorCoffe {
+    coffe: Box<dyn Coffe>,
+}
+
+impl SugarDecoratorCoffe {
+    fn new(coffe: Box<dyn Coffe>) -> Self {
+        SugarDecoratorCoffe { coffe }
+    }
+}
+
+impl Coffe for SugarDecoratorCoffe {
+    fn cost(&self) -> Result<Money, CurrencyMismatch> {
+        Surcharge::Fixed(Money::new(5, 0, Currency::Rub))
+            .apply(self.coffe.cost()?)
+    }
+    fn description(&self) -> String {
+        format!("{} with sugar", self.coffe.description())
+    }
+    fn layers(&self) -> Result<Vec<Layer>, CurrencyMismatch> {
+        push_layer(self, self.coffe.as_ref(), "sugar")
+    }
+    fn remove_layer(mut self: Box<Self>, name: &str) -> Box<dyn Coffe> {
+        if name == "sugar" {
+            return self.coffe;
+        }
+        self.coffe = self.coffe.remove_layer(name);
+        self
+    }
+}
+
+struct SyrupDecoratorCoffe {
+    coffe: Box<dyn Coffe>,
+    flavor: String,
+}
+
+impl SyrupDecoratorCoffe {
+    fn new(coffe: Box<dyn Coffe>, flavor: &str) -> Self {
+        SyrupDecoratorCoffe {
+            coffe,
+            flavor: flavor.to_string(),
+        }
+    }
+}
+
+impl Coffe for SyrupDecoratorCoffe {
+    fn cost(&self) -> Result<Money, CurrencyMismatch> {
+        Surcharge::Fixed(Money::new(15, 0, Currency::Rub))
+            .apply(self.coffe.cost()?)
+    }
+    fn description(&self) -> String {
+        format!("{} with {} syrup", self.coffe.description(), self.flavor)
+    }
+    fn layers(&self) -> Result<Vec<Layer>, CurrencyMismatch> {
+        let name = format!("{} syrup", self.flavor);
+        push_layer(self, self.coffe.as_ref(), &name)
+    }
+    fn remove_layer(mut self: Box<Self>, name: &str) -> Box<dyn Coffe> {
+        if name == format!("{} syrup", self.flavor) {
+            return self.coffe;
+        }
+        self.coffe = self.coffe.remove_layer(name);
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Size {
+    Small,
+    Medium,
+    Large,
+}
+
+impl Size {
+    fn name(&self) -> &'static str {
+        match self {
+            Size::Small => "small",
+            Size::Medium => "medium",
+            Size::Large => "large",
+        }
+    }
+    fn surcharge(&self) -> Surcharge {
+        let basis_points = match self {
+            Size::Small => -2000,
+            Size::Medium => 0,
+            Size::Large => 3000,
+        };
+        Surcharge::Percent {
+            basis_points,
+            rounding: Rounding::HalfEven,
+        }
+    }
+}
+
+struct SizeDecoratorCoffe {
+    coffe: Box<dyn Coffe>,
+    size: Size,
+}
+
+impl SizeDecoratorCoffe {
+    fn new(coffe: Box<dyn Coffe>, size: Size) -> Self {
+        SizeDecoratorCoffe { coffe, size }
+    }
+}
+
+impl Coffe for SizeDecoratorCoffe {
+    fn cost(&self) -> Result<Money, CurrencyMismatch> {
+        self.size.surcharge().apply(self.coffe.cost()?)
+    }
+    fn description(&self) -> String {
+        format!("{}, {}", self.coffe.description(), self.size.name())
+    }
+    fn layers(&self) -> Result<Vec<Layer>, CurrencyMismatch> {
+        push_layer(self, self.coffe.as_ref(), self.size.name())
+    }
+    fn remove_layer(mut self: Box<Self>, name: &str) -> Box<dyn Coffe> {
+        if name == self.size.name() {
+            return self.coffe;
+        }
+        self.coffe = self.coffe.remove_layer(name);
+        self
+    }
+}
+
+fn print_receipt(coffe: &dyn Coffe) -> Result<(), CurrencyMismatch> {
+    println!("Description: {}", coffe.description());
+    for layer in coffe.layers()? {
+        println!("  + {:<14} {:>12}", layer.name, layer.price.to_string());
+    }
+    println!("Cost: {}", coffe.cost()?);
+    Ok(())
+}
+
+/*
+ * Декораторы для произвольных функций вида Fn(A) -> Result<B, E>.
+ * Каждый вызов builder-метода оборачивает текущую функцию в новое замыкание,
+ * поэтому порядок вызовов задает порядок обертывания:
+ * decorate(f).attempts(3).timed() замеряет время всех попыток вместе.
+ * Обернутая функция и ее метрики потокобезопасны, поэтому один клиент
+ * сервиса можно вызывать из нескольких потоков
+ */
+#[derive(Default)]
+struct Metrics {
+    calls: AtomicU32,
+    failures: AtomicU32,
+    total_nanos: AtomicU64,
+}
+
+impl Metrics {
+    fn record(&self, elapsed: Duration, ok: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+    fn calls(&self) -> u32 {
+        self.calls.load(Ordering::Relaxed)
+    }
+    fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+    fn total_time(&self) -> Duration {
+        Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed))
+    }
+}
+
+type Function<A, B, E> = dyn Fn(A) -> Result<B, E> + Send + Sync;
+
+struct Decorated<A, B, E> {
+    f: Box<Function<A, B, E>>,
+    metrics: Arc<Metrics>,
+}
+
+fn decorate<A, B, E, F>(f: F) -> Decorated<A, B, E>
+where
+    F: Fn(A) -> Result<B, E> + Send + Sync + 'static,
+{
+    Decorated {
+        f: Box::new(f),
+        metrics: Default::default(),
+    }
+}
+
+impl<A: 'static, B: 'static, E: 'static> Decorated<A, B, E> {
+    fn call(&self, arg: A) -> Result<B, E> {
+        (self.f)(arg)
+    }
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+    fn wrap<F>(self, wrapper: F) -> Self
+    where
+        F: Fn(&Function<A, B, E>, A) -> Result<B, E> + Send + Sync + 'static,
+    {
+        let inner = self.f;
+        Decorated {
+            f: Box::new(move |arg| wrapper(inner.as_ref(), arg)),
+            metrics: self.metrics,
+        }
+    }
+    /*
+     * Всего не больше attempts вызовов, считая первый, но хотя бы один.
+     * Между попытками экспоненциальная задержка: 10мс, 20мс, 40мс...
+     */
+    fn attempts(self, attempts: u32) -> Self
+    where
+        A: Clone,
+    {
+        self.attempts_with_backoff(attempts, Duration::from_millis(10))
+    }
+    fn attempts_with_backoff(self, attempts: u32, initial: Duration) -> Self
+    where
+        A: Clone,
+    {
+        self.wrap(move |f, arg| {
+            let mut delay = initial;
+            let mut result = f(arg.clone());
+            for _ in 1..attempts {
+                if result.is_ok() {
+                    break;
+                }
+                thread::sleep(delay);
+                delay *= 2;
+                result = f(arg.clone());
+            }
+            result
+        })
+    }
+    fn timed(self) -> Self {
+        let metrics = self.metrics.clone();
+        self.wrap(move |f, arg| {
+            let start = Instant::now();
+            let result = f(arg);
+            metrics.record(start.elapsed(), result.is_ok());
+            result
+        })
+    }
+    fn logged(self, name: &str) -> Self
+    where
+        A: fmt::Debug,
+        B: fmt::Debug,
+        E: fmt::Debug,
+    {
+        let name = name.to_string();
+        self.wrap(move |f, arg| {
+            println!("-> {}({:?})", name, arg);
+            let result = f(arg);
+            println!("<- {}: {:?}", name, result);
+            result
+        })
+    }
+    /*
+     * Кешируются только успешные результаты, ошибки не запоминаем.
+     * Сама функция вызывается без блокировки кеша, чтобы медленный вызов
+     * не задерживал остальные потоки
+     */
+    fn memoize(self) -> Self
+    where
+        A: Eq + Hash + Clone + Send,
+        B: Clone + Send,
+    {
+        let cache: Mutex<HashMap<A, B>> = Default::default();
+        self.wrap(move |f, arg| {
+            let cached = cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&arg)
+                .cloned();
+            if let Some(value) = cached {
+                return Ok(value);
+            }
+            let value = f(arg.clone())?;
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(arg, value.clone());
+            Ok(value)
+        })
+    }
 }
 
 fn main() {
     let milk_coffe = MilkDecoratorCoffe::new(Box::new(SimpleCoffe {}));
+    print_receipt(&milk_coffe).unwrap();
+
+    // Декораторы можно накладывать в любом порядке и в любом количестве
+    let coffe: Box<dyn Coffe> = Box::new(SimpleCoffe {});
+    let coffe = Box::new(MilkDecoratorCoffe::new(coffe));
+    let coffe = Box::new(SyrupDecoratorCoffe::new(coffe, "vanilla"));
+    let coffe = Box::new(SugarDecoratorCoffe::new(coffe));
+    let coffe: Box<dyn Coffe> =
+        Box::new(SizeDecoratorCoffe::new(coffe, Size::Large));
+    print_receipt(coffe.as_ref()).unwrap();
+
+    // Клиент передумал и отказался от сиропа
+    let coffe = coffe.remove_layer("vanilla syrup");
+    print_receipt(coffe.as_ref()).unwrap();
+
+    // Цена зависит от размера стакана
+    for size in [Size::Small, Size::Medium, Size::Large] {
+        let sized = SizeDecoratorCoffe::new(Box::new(SimpleCoffe {}), size);
+        println!("{}: {}", size.name(), sized.cost().unwrap());
+    }
+
+    // Половина копейки округляется по-разному в зависимости от правила
+    let price = Money::new(1, 5, Currency::Rub);
+    for rounding in [
+        Rounding::Floor,
+        Rounding::Ceil,
+        Rounding::HalfUp,
+        Rounding::HalfEven,
+    ] {
+        println!(
+            "10% of {} rounded {:?}: {}",
+            price,
+            rounding,
+            price.percent(1000, rounding)
+        );
+    }
+
+    // Надбавка в долларах к цене в рублях: ошибка вместо паники
+    let dollars = Surcharge::Fixed(Money::new(1, 0, Currency::Usd));
+    if let Err(err) = dollars.apply(coffe.cost().unwrap()) {
+        println!("Error: {}", err);
+    }
+
+    // Нестабильный сервис: первые два вызова завершаются ошибкой
+    let failures_left = AtomicU32::new(2);
+    let fetch_price = decorate(move |id: u32| {
+        let failing = failures_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if failing {
+            return Err("service unavailable".to_string());
+        }
+        Ok(Money::new(100 + id as i64, 0, Currency::Rub))
+    })
+    .logged("fetch_price")
+    .attempts(3)
+    .timed()
+    .memoize();
+    println!("{:?}", fetch_price.call(7).map(|m| m.to_string()));
+    // Повторный вызов обслуживается из кеша и не доходит до сервиса
+    println!("{:?}", fetch_price.call(7).map(|m| m.to_string()));
+    // Один клиент на несколько потоков
+    thread::scope(|scope| {
+        for id in [8, 9] {
+            let fetch_price = &fetch_price;
+            scope.spawn(move || fetch_price.call(id));
+        }
+    });
+    let metrics = fetch_price.metrics();
     println!(
-        "Description: {}\nCost: {}",
-        milk_coffe.description(),
-        milk_coffe.cost()
+        "calls: {}, failures: {}, total time: {:?}",
+        metrics.calls(),
+        metrics.failures(),
+        metrics.total_time()
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [Rounding; 4] = [
+        Rounding::Floor,
+        Rounding::Ceil,
+        Rounding::HalfUp,
+        Rounding::HalfEven,
+    ];
+
+    // Половины копейки: 0.5, 1.5, -0.5 и -1.5, результаты в порядке MODES
+    #[test]
+    fn rounding_of_half_cents() {
+        let cases = [
+            (5, [0, 1, 1, 0]),
+            (15, [1, 2, 2, 2]),
+            (-5, [-1, 0, -1, 0]),
+            (-15, [-2, -1, -2, -2]),
+        ];
+        for (tenths, expected) in cases {
+            for (rounding, expected) in MODES.into_iter().zip(expected) {
+                assert_eq!(
+                    rounding.div(tenths, 10),
+                    expected,
+                    "{} / 10 rounded {:?}",
+                    tenths,
+                    rounding
+                );
+            }
+        }
+    }
+
+    // 10% от 1.05 и от -1.05 это ровно половина копейки
+    #[test]
+    fn percent_of_half_cent_price() {
+        let positive = Money::new(1, 5, Currency::Rub);
+        let negative = Money::new(-1, -5, Currency::Rub);
+        let cents =
+            |money: Money, rounding| money.percent(1000, rounding).cents;
+        let results: Vec<_> =
+            MODES.iter().map(|r| cents(positive, *r)).collect();
+        assert_eq!(results, [10, 11, 11, 10]);
+        let results: Vec<_> =
+            MODES.iter().map(|r| cents(negative, *r)).collect();
+        assert_eq!(results, [-11, -10, -11, -10]);
+    }
+
+    #[test]
+    fn stacked_decorators_total() {
+        let coffe: Box<dyn Coffe> = Box::new(SimpleCoffe {});
+        let coffe = Box::new(MilkDecoratorCoffe::new(coffe));
+        let coffe = Box::new(SyrupDecoratorCoffe::new(coffe, "vanilla"));
+        let coffe = Box::new(SugarDecoratorCoffe::new(coffe));
+        let coffe = SizeDecoratorCoffe::new(coffe, Size::Large);
+        assert_eq!(coffe.cost().unwrap(), Money::new(169, 0, Currency::Rub));
+        let layers: Vec<_> = coffe
+            .layers()
+            .unwrap()
+            .iter()
+            .map(|l| l.price.cents)
+            .collect();
+        assert_eq!(layers, [1000, 1500, 500, 3900]);
+    }
+
+    // attempts(3) это три вызова всего, а не три повтора после первого
+    #[test]
+    fn attempts_counts_first_call() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let failing = decorate(move |_: ()| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>("down")
+        })
+        .attempts_with_backoff(3, Duration::ZERO);
+        assert!(failing.call(()).is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn decorated_is_shareable_between_threads() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        let square = decorate(|x: u32| Ok::<_, ()>(x * x)).timed().memoize();
+        assert_send_sync(&square);
+        thread::scope(|scope| {
+            for x in 0..4 {
+                let square = &square;
+                scope.spawn(move || assert_eq!(square.call(x), Ok(x * x)));
+            }
+        });
+        assert_eq!(square.metrics().calls(), 4);
+    }
+
+    #[test]
+    fn mixed_currencies_are_an_error() {
+        let rub = Money::new(1, 0, Currency::Rub);
+        let usd = Money::new(1, 0, Currency::Usd);
+        assert!(rub.checked_add(usd).is_err());
+        assert!(rub.checked_sub(usd).is_err());
+        assert!(Surcharge::Fixed(usd).apply(rub).is_err());
+    }
+}
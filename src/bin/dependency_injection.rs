@@ -0,0 +1,321 @@
+/**
+ * Внедрение зависимостей (Dependency Injection) — это способ собирать объекты,
+ * при котором объект не создает свои зависимости сам и не достает их из
+ * глобальных одиночек, а получает их снаружи. Сборкой занимается контейнер:
+ * в нем регистрируются поставщики (providers), а он создает объекты по типу
+ * и сам подставляет им нужные зависимости.
+ *
+ * Время жизни объектов задается при регистрации:
+ * - singleton: один экземпляр на весь контейнер
+ * - transient: новый экземпляр при каждом запросе
+ * - scoped: один экземпляр в пределах области (например, одного запроса)
+ *
+ * Когда использовать:
+ * - Когда реализации нужно подменять из конфигурации или в тестах
+ * - Когда объекты связаны вручную в main и это связывание повторяется
+ *
+ * Пример самостоятельный: остальные примеры (singleton.rs, abstract_factory.rs)
+ * по-прежнему связывают объекты вручную, а виджеты и фабрики здесь объявлены
+ * заново в упрощенном виде, только чтобы было что внедрять
+**/
+use std::{
+    any::{type_name, Any, TypeId},
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    env, fmt,
+    rc::Rc,
+    sync::Arc,
+};
+
+#[derive(Debug)]
+enum DiError {
+    // Для типа не зарегистрирован поставщик
+    Missing(&'static str),
+    // Цепочка типов, которые зависят друг от друга по кругу
+    Cycle(Vec<&'static str>),
+}
+
+impl fmt::Display for DiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiError::Missing(name) => write!(f, "no provider for {}", name),
+            DiError::Cycle(path) => {
+                write!(f, "dependency cycle: {}", path.join(" -> "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Lifetime {
+    Singleton,
+    Transient,
+    Scoped,
+}
+
+type Factory = Rc<dyn Fn(&Scope) -> Result<Box<dyn Any>, DiError>>;
+
+struct Provider {
+    lifetime: Lifetime,
+    factory: Factory,
+    // Копирует закешированный экземпляр, чтобы отдать его очередному клиенту
+    clone: fn(&dyn Any) -> Box<dyn Any>,
+}
+
+fn clone_any<T: Clone + 'static>(value: &dyn Any) -> Box<dyn Any> {
+    Box::new(value.downcast_ref::<T>().unwrap().clone())
+}
+
+struct Container {
+    providers: HashMap<TypeId, Provider>,
+    singletons: RefCell<HashMap<TypeId, Box<dyn Any>>>,
+    // Типы, которые создаются прямо сейчас, для поиска циклов
+    resolving: RefCell<Vec<(TypeId, &'static str)>>,
+}
+
+impl Container {
+    fn new() -> Self {
+        Container {
+            providers: HashMap::new(),
+            singletons: Default::default(),
+            resolving: Default::default(),
+        }
+    }
+    fn register<T: Clone + 'static>(
+        &mut self,
+        lifetime: Lifetime,
+        factory: impl Fn(&Scope) -> Result<T, DiError> + 'static,
+    ) {
+        let factory: Factory = Rc::new(move |scope| {
+            factory(scope).map(|value| Box::new(value) as Box<dyn Any>)
+        });
+        let provider = Provider {
+            lifetime,
+            factory,
+            clone: clone_any::<T>,
+        };
+        self.providers.insert(TypeId::of::<T>(), provider);
+    }
+    /*
+     * Singleton и scoped экземпляры раздаются копиями, поэтому их регистрируют
+     * как Arc<dyn Trait>. Transient может быть и Box<dyn Trait>
+     */
+    fn singleton<T: Clone + 'static>(
+        &mut self,
+        factory: impl Fn(&Scope) -> Result<T, DiError> + 'static,
+    ) {
+        self.register(Lifetime::Singleton, factory);
+    }
+    fn scoped<T: Clone + 'static>(
+        &mut self,
+        factory: impl Fn(&Scope) -> Result<T, DiError> + 'static,
+    ) {
+        self.register(Lifetime::Scoped, factory);
+    }
+    fn transient<T: 'static>(
+        &mut self,
+        factory: impl Fn(&Scope) -> Result<T, DiError> + 'static,
+    ) {
+        let factory: Factory = Rc::new(move |scope| {
+            factory(scope).map(|value| Box::new(value) as Box<dyn Any>)
+        });
+        let provider = Provider {
+            lifetime: Lifetime::Transient,
+            factory,
+            clone: |_| unreachable!("transient values are never cached"),
+        };
+        self.providers.insert(TypeId::of::<T>(), provider);
+    }
+    fn create_scope(&self) -> Scope<'_> {
+        Scope {
+            container: self,
+            cache: Default::default(),
+        }
+    }
+    // Вне области scoped экземпляр живет только в пределах одного resolve
+    fn resolve<T: 'static>(&self) -> Result<T, DiError> {
+        self.create_scope().resolve()
+    }
+}
+
+struct Scope<'a> {
+    container: &'a Container,
+    cache: RefCell<HashMap<TypeId, Box<dyn Any>>>,
+}
+
+impl Scope<'_> {
+    fn resolve<T: 'static>(&self) -> Result<T, DiError> {
+        let value = self.resolve_any(TypeId::of::<T>(), type_name::<T>())?;
+        Ok(*value.downcast::<T>().unwrap())
+    }
+    fn resolve_any(
+        &self,
+        id: TypeId,
+        name: &'static str,
+    ) -> Result<Box<dyn Any>, DiError> {
+        let container = self.container;
+        let provider =
+            container.providers.get(&id).ok_or(DiError::Missing(name))?;
+        let cache = match provider.lifetime {
+            Lifetime::Singleton => Some(&container.singletons),
+            Lifetime::Scoped => Some(&self.cache),
+            Lifetime::Transient => None,
+        };
+        if let Some(value) = cache.and_then(|cache| {
+            cache
+                .borrow()
+                .get(&id)
+                .map(|value| (provider.clone)(value.as_ref()))
+        }) {
+            return Ok(value);
+        }
+        {
+            let mut resolving = container.resolving.borrow_mut();
+            if let Some(start) =
+                resolving.iter().position(|(other, _)| *other == id)
+            {
+                let mut path: Vec<_> =
+                    resolving[start..].iter().map(|(_, name)| *name).collect();
+                path.push(name);
+                resolving.clear();
+                return Err(DiError::Cycle(path));
+            }
+            resolving.push((id, name));
+        }
+        // Фабрика сама может вызывать resolve, поэтому заимствования тут отпущены
+        let result = (provider.factory)(self);
+        container
+            .resolving
+            .borrow_mut()
+            .retain(|(other, _)| *other != id);
+        let value = result?;
+        if let Some(cache) = cache {
+            let copy = (provider.clone)(value.as_ref());
+            cache.borrow_mut().insert(id, copy);
+        }
+        Ok(value)
+    }
+}
+
+trait Button {
+    fn click(&self) -> String;
+}
+struct ButtonQT {}
+impl Button for ButtonQT {
+    fn click(&self) -> String {
+        "Clicked QT button".to_string()
+    }
+}
+struct ButtonGTK {}
+impl Button for ButtonGTK {
+    fn click(&self) -> String {
+        "Clicked GTK button".to_string()
+    }
+}
+trait WidgetFactory {
+    fn create_button(&self) -> Box<dyn Button>;
+}
+struct QtWidgetFactory {}
+impl WidgetFactory for QtWidgetFactory {
+    fn create_button(&self) -> Box<dyn Button> {
+        Box::new(ButtonQT {})
+    }
+}
+struct GtkWidgetFactory {}
+impl WidgetFactory for GtkWidgetFactory {
+    fn create_button(&self) -> Box<dyn Button> {
+        Box::new(ButtonGTK {})
+    }
+}
+
+trait Logger {
+    fn log(&self, message: &str);
+}
+struct ConsoleLogger {}
+impl Logger for ConsoleLogger {
+    fn log(&self, message: &str) {
+        println!("[log] {}", message);
+    }
+}
+
+// Данные одного запроса, общие для всех объектов внутри области
+struct RequestContext {
+    id: u32,
+}
+
+struct Window {
+    factory: Arc<dyn WidgetFactory>,
+    logger: Arc<dyn Logger>,
+    request: Arc<RequestContext>,
+}
+impl Window {
+    fn show(&self) {
+        let button = self.factory.create_button();
+        self.logger.log(&format!(
+            "request {}: {}",
+            self.request.id,
+            button.click()
+        ));
+    }
+}
+
+// Две службы, которые по ошибке зависят друг от друга
+#[derive(Clone)]
+struct Billing {}
+#[derive(Clone)]
+struct Accounts {}
+
+fn main() {
+    // Тема берется из конфигурации: WIDGET_THEME=qt cargo run --bin ...
+    let theme = env::var("WIDGET_THEME").unwrap_or_else(|_| "gtk".to_string());
+
+    let mut container = Container::new();
+    // Незнакомая тема это ошибка конфигурации, а не повод молча взять gtk
+    match theme.to_lowercase().as_str() {
+        "qt" => container.singleton::<Arc<dyn WidgetFactory>>(|_| {
+            Ok(Arc::new(QtWidgetFactory {}))
+        }),
+        "gtk" => container.singleton::<Arc<dyn WidgetFactory>>(|_| {
+            Ok(Arc::new(GtkWidgetFactory {}))
+        }),
+        _ => {
+            println!("Error: unknown theme '{}', known themes: gtk, qt", theme);
+            return;
+        }
+    }
+    container.singleton::<Arc<dyn Logger>>(|_| Ok(Arc::new(ConsoleLogger {})));
+    let next_request = Rc::new(Cell::new(1));
+    container.scoped::<Arc<RequestContext>>(move |_| {
+        let id = next_request.get();
+        next_request.set(id + 1);
+        Ok(Arc::new(RequestContext { id }))
+    });
+    container.transient::<Box<Window>>(|scope| {
+        Ok(Box::new(Window {
+            factory: scope.resolve()?,
+            logger: scope.resolve()?,
+            request: scope.resolve()?,
+        }))
+    });
+
+    // Два окна в одной области делят контекст запроса, в новой области он свой
+    let scope = container.create_scope();
+    scope.resolve::<Box<Window>>().unwrap().show();
+    scope.resolve::<Box<Window>>().unwrap().show();
+    container.resolve::<Box<Window>>().unwrap().show();
+
+    if let Err(err) = container.resolve::<Box<dyn Button>>() {
+        println!("Error: {}", err);
+    }
+    container.singleton::<Billing>(|scope| {
+        scope.resolve::<Accounts>()?;
+        Ok(Billing {})
+    });
+    container.transient::<Accounts>(|scope| {
+        scope.resolve::<Billing>()?;
+        Ok(Accounts {})
+    });
+    if let Err(err) = container.resolve::<Billing>() {
+        println!("Error: {}", err);
+    }
+}
@@ -12,46 +12,278 @@
  *   несколько присваиваний, то имеет смысл делегировать задачу выделенной
  *   фабрике, а не повторять повсюду один и тот же код.
 **/
+use std::fmt;
 
-#[derive(Debug)]
-#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Color {
     White,
     Black,
+    Brown,
+    Gray,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Material {
+    Wood,
+    Steel,
+    Glass,
+}
+
+// Вид двери из каталога: размеры в миллиметрах и материал
+#[derive(Debug, Clone, Copy)]
+struct DoorKind {
+    name: &'static str,
+    width: u32,
+    height: u32,
+    material: Material,
 }
 
+const CATALOG: &[DoorKind] = &[
+    DoorKind {
+        name: "interior",
+        width: 800,
+        height: 2000,
+        material: Material::Wood,
+    },
+    DoorKind {
+        name: "entrance",
+        width: 900,
+        height: 2050,
+        material: Material::Steel,
+    },
+    DoorKind {
+        name: "bathroom",
+        width: 600,
+        height: 2000,
+        material: Material::Wood,
+    },
+    DoorKind {
+        name: "patio",
+        width: 1500,
+        height: 2100,
+        material: Material::Glass,
+    },
+];
+
 #[derive(Debug)]
 #[allow(unused)]
 struct Door {
     name: String,
     color: Color,
+    kind: DoorKind,
 }
 
 impl Door {
-    fn new(name: &str, color: Color) -> Self {
+    fn new(name: &str, color: Color, kind: DoorKind) -> Self {
         Door {
             name: name.to_string(),
             color,
+            kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Name,
+    Color,
+    Kind,
+}
+
+#[derive(Debug)]
+enum DoorError {
+    MissingField(Field),
+    NameTooLong { max: usize, actual: usize },
+    ColorNotAllowed(Color),
+    UnknownKind(String),
+}
+
+impl fmt::Display for DoorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DoorError::MissingField(field) => {
+                write!(f, "field {:?} is required", field)
+            }
+            DoorError::NameTooLong { max, actual } => write!(
+                f,
+                "name is {} characters long, at most {} allowed",
+                actual, max
+            ),
+            DoorError::ColorNotAllowed(color) => {
+                write!(f, "color {:?} is not allowed", color)
+            }
+            DoorError::UnknownKind(kind) => {
+                write!(f, "no door kind '{}' in the catalog", kind)
+            }
+        }
+    }
+}
+
+// Заказ двери: незаданные поля фабрика заполнит сама, если они не обязательны
+#[derive(Default)]
+struct DoorSpec<'a> {
+    name: Option<&'a str>,
+    color: Option<Color>,
+    kind: Option<&'a str>,
+}
+
+// Правила проверки, у каждой фабрики свои
+struct DoorRules {
+    // Длина имени в символах, а не в байтах, иначе кириллица режется вдвое
+    max_name_chars: usize,
+    allowed_colors: Vec<Color>,
+    required: Vec<Field>,
+}
+
+impl Default for DoorRules {
+    fn default() -> Self {
+        DoorRules {
+            max_name_chars: 10,
+            allowed_colors: vec![
+                Color::White,
+                Color::Black,
+                Color::Brown,
+                Color::Gray,
+            ],
+            required: vec![Field::Name],
         }
     }
 }
 
-struct DoorFactory {}
+struct DoorFactory {
+    rules: DoorRules,
+    catalog: &'static [DoorKind],
+}
 
 impl DoorFactory {
-    fn make(name: &str, color: Color) -> Option<Door> {
+    fn new(rules: DoorRules) -> Self {
+        DoorFactory {
+            rules,
+            catalog: CATALOG,
+        }
+    }
+    fn kind(&self, name: &str) -> Option<DoorKind> {
+        self.catalog.iter().find(|kind| kind.name == name).copied()
+    }
+    fn make(&self, spec: &DoorSpec) -> Result<Door, DoorError> {
         /*
          * Какая та дополнительная логика, которую не стоит раскрывать или
          * дополнительные проверки
          */
-        if name.len() > 10 {
-            return None;
+        let rules = &self.rules;
+        let missing = [
+            (Field::Name, spec.name.is_none()),
+            (Field::Color, spec.color.is_none()),
+            (Field::Kind, spec.kind.is_none()),
+        ];
+        for (field, is_missing) in missing {
+            if is_missing && rules.required.contains(&field) {
+                return Err(DoorError::MissingField(field));
+            }
         }
-        Some(Door::new(name, color))
+        /*
+         * Без вида берется первый из каталога, без цвета первый разрешенный.
+         * Если брать нечего, поле считается обязательным
+         */
+        let kind = match spec.kind {
+            Some(name) => self
+                .kind(name)
+                .ok_or_else(|| DoorError::UnknownKind(name.to_string()))?,
+            None => *self
+                .catalog
+                .first()
+                .ok_or(DoorError::MissingField(Field::Kind))?,
+        };
+        let name = spec.name.unwrap_or(kind.name);
+        let length = name.chars().count();
+        if length > rules.max_name_chars {
+            return Err(DoorError::NameTooLong {
+                max: rules.max_name_chars,
+                actual: length,
+            });
+        }
+        let color = spec
+            .color
+            .or_else(|| rules.allowed_colors.first().copied())
+            .ok_or(DoorError::MissingField(Field::Color))?;
+        if !rules.allowed_colors.contains(&color) {
+            return Err(DoorError::ColorNotAllowed(color));
+        }
+        Ok(Door::new(name, color, kind))
     }
 }
 
 fn main() {
-    let door = DoorFactory::make("Wood Door", Color::White).unwrap();
+    let factory = DoorFactory::new(DoorRules::default());
+    let door = factory
+        .make(&DoorSpec {
+            name: Some("Wood Door"),
+            color: Some(Color::White),
+            ..Default::default()
+        })
+        .unwrap();
     println!("{:?}", door);
+    // 10 символов кириллицей занимают 20 байт, но это допустимое имя
+    let door = factory.make(&DoorSpec {
+        name: Some("Дубовая дв"),
+        kind: Some("entrance"),
+        ..Default::default()
+    });
+    println!("{:?}", door);
+
+    // Фабрика входных дверей: только темные цвета, вид указывать обязательно
+    let strict = DoorFactory::new(DoorRules {
+        max_name_chars: 20,
+        allowed_colors: vec![Color::Black, Color::Gray],
+        required: vec![Field::Name, Field::Kind],
+    });
+    let specs = [
+        DoorSpec {
+            name: Some("Front"),
+            color: Some(Color::White),
+            kind: Some("entrance"),
+        },
+        DoorSpec {
+            name: Some("Front"),
+            ..Default::default()
+        },
+        DoorSpec {
+            name: Some("Garden"),
+            kind: Some("garage"),
+            ..Default::default()
+        },
+        DoorSpec {
+            name: Some("Очень длинное название двери"),
+            kind: Some("patio"),
+            ..Default::default()
+        },
+        DoorSpec {
+            name: Some("Terrace"),
+            kind: Some("patio"),
+            ..Default::default()
+        },
+    ];
+    for spec in &specs {
+        match strict.make(spec) {
+            Ok(door) => println!("{:?}", door),
+            Err(err) => println!("Error: {}", err),
+        }
+    }
+    // Без разрешенных цветов цвет по умолчанию выбрать нельзя
+    let unpainted = DoorFactory::new(DoorRules {
+        allowed_colors: vec![],
+        ..Default::default()
+    });
+    if let Err(err) = unpainted.make(&DoorSpec {
+        name: Some("Plain"),
+        ..Default::default()
+    }) {
+        println!("Error: {}", err);
+    }
+    for kind in CATALOG {
+        println!(
+            "{}: {}x{} mm, {:?}",
+            kind.name, kind.width, kind.height, kind.material
+        );
+    }
 }
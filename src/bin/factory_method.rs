@@ -16,9 +16,102 @@
  *   подклассы динамически определяются в ходе выполнения (runtime). То есть
  *   когда клиент не знает, какой именно подкласс может ему понадобиться.
 */
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
+/*
+ * Груз, который нужно доставить. Вес можно взять готовым у дерева грузов
+ * из composite.rs: контейнер сам суммирует вес всего, что в него положено
+ */
+struct Shipment {
+    description: String,
+    weight_kg: u32,
+    origin: String,
+    destination: String,
+}
+
+#[derive(Debug)]
+struct DeliveryEstimate {
+    transport: &'static str,
+    // Сколько машин нужно, чтобы увезти груз за один рейс
+    vehicles: u32,
+    hours: u32,
+    // Стоимость в центах
+    cost: u64,
+}
+impl fmt::Display for DeliveryEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} x {}, {} h, {}",
+            self.vehicles,
+            self.transport,
+            self.hours,
+            money(self.cost)
+        )
+    }
+}
+
+/*
+ * Транспорт описывает себя характеристиками, а оценку доставки по ним
+ * считает общий метод deliver
+ */
 trait Transport {
-    fn deliver(&self);
+    fn name(&self) -> &'static str;
+    // Грузоподъемность одной машины в килограммах
+    fn capacity_kg(&self) -> u32;
+    fn speed_kmh(&self) -> u32;
+    // Стоимость километра для одной машины в центах
+    fn cost_per_km(&self) -> u32;
+    // Погрузка и оформление в часах
+    fn handling_hours(&self) -> u32 {
+        0
+    }
+    /*
+     * Тяжелый груз делится между несколькими машинами, они идут одновременно.
+     * Характеристики задает сторонний код, поэтому нулевую грузоподъемность
+     * или скорость нельзя подставлять в деление: такой транспорт ничего
+     * не довезет, и планировщик должен его пропустить, а не упасть
+     */
+    fn deliver(
+        &self,
+        shipment: &Shipment,
+        distance_km: u32,
+    ) -> Result<DeliveryEstimate, InvalidTransport> {
+        let invalid = |reason| InvalidTransport {
+            transport: self.name(),
+            reason,
+        };
+        if self.capacity_kg() == 0 {
+            return Err(invalid("zero capacity"));
+        }
+        if self.speed_kmh() == 0 {
+            return Err(invalid("zero speed"));
+        }
+        let vehicles = shipment.weight_kg.div_ceil(self.capacity_kg()).max(1);
+        Ok(DeliveryEstimate {
+            transport: self.name(),
+            vehicles,
+            hours: distance_km
+                .div_ceil(self.speed_kmh())
+                .saturating_add(self.handling_hours()),
+            cost: (vehicles as u64 * distance_km as u64)
+                .saturating_mul(self.cost_per_km() as u64),
+        })
+    }
+}
+
+// Транспорт, для которого нельзя посчитать доставку
+struct InvalidTransport {
+    transport: &'static str,
+    reason: &'static str,
+}
+impl fmt::Display for InvalidTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "skipped, {} has {}", self.transport, self.reason)
+    }
 }
 
 struct Car {}
@@ -28,8 +121,20 @@ impl Car {
     }
 }
 impl Transport for Car {
-    fn deliver(&self) {
-        println!("Delivery by car");
+    fn name(&self) -> &'static str {
+        "car"
+    }
+    fn capacity_kg(&self) -> u32 {
+        1_000
+    }
+    fn speed_kmh(&self) -> u32 {
+        60
+    }
+    fn cost_per_km(&self) -> u32 {
+        150
+    }
+    fn handling_hours(&self) -> u32 {
+        1
     }
 }
 struct Ship {}
@@ -39,8 +144,20 @@ impl Ship {
     }
 }
 impl Transport for Ship {
-    fn deliver(&self) {
-        println!("Delivery by ship");
+    fn name(&self) -> &'static str {
+        "ship"
+    }
+    fn capacity_kg(&self) -> u32 {
+        20_000
+    }
+    fn speed_kmh(&self) -> u32 {
+        30
+    }
+    fn cost_per_km(&self) -> u32 {
+        400
+    }
+    fn handling_hours(&self) -> u32 {
+        24
     }
 }
 
@@ -72,13 +189,300 @@ impl Logistics for SeaLogistics {
         Box::new(Ship::new())
     }
 }
+
+/*
+ * Реестр логистик по имени. Клиент выбирает логистику строкой из
+ * конфигурации или заказа, а новые логистики добавляются через register
+ * без изменения этого файла
+ */
+struct LogisticsRegistry {
+    logistics: BTreeMap<String, Box<dyn Logistics>>,
+}
+impl LogisticsRegistry {
+    fn new() -> Self {
+        LogisticsRegistry {
+            logistics: BTreeMap::new(),
+        }
+    }
+    // Реестр со встроенными логистиками
+    fn with_builtin() -> Self {
+        let mut registry = LogisticsRegistry::new();
+        registry.register("road", RoadLogistics::new());
+        registry.register("sea", SeaLogistics::new());
+        registry
+    }
+    fn register(&mut self, name: &str, logistics: impl Logistics + 'static) {
+        self.logistics
+            .insert(name.to_lowercase(), Box::new(logistics));
+    }
+    fn get(&self, name: &str) -> Result<&dyn Logistics, UnknownLogistics> {
+        match self.logistics.get(&name.to_lowercase()) {
+            Some(logistics) => Ok(logistics.as_ref()),
+            None => Err(UnknownLogistics {
+                requested: name.to_string(),
+                known: self.logistics.keys().cloned().collect(),
+            }),
+        }
+    }
+    fn iter(&self) -> impl Iterator<Item = (&str, &dyn Logistics)> {
+        self.logistics
+            .iter()
+            .map(|(name, logistics)| (name.as_str(), logistics.as_ref()))
+    }
+}
+
+#[derive(Debug)]
+struct UnknownLogistics {
+    requested: String,
+    known: Vec<String>,
+}
+impl fmt::Display for UnknownLogistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown logistics '{}', known: {}",
+            self.requested,
+            self.known.join(", ")
+        )
+    }
+}
+
+// Расстояния между городами для каждой логистики, маршруты двусторонние
+struct Routes {
+    distances: HashMap<(String, String, String), u32>,
+}
+impl Routes {
+    fn new() -> Self {
+        Routes {
+            distances: HashMap::new(),
+        }
+    }
+    fn add(&mut self, logistics: &str, a: &str, b: &str, km: u32) {
+        for (from, to) in [(a, b), (b, a)] {
+            let key = (logistics.to_string(), from.to_string(), to.to_string());
+            self.distances.insert(key, km);
+        }
+    }
+    fn distance(&self, logistics: &str, from: &str, to: &str) -> Option<u32> {
+        let key = (logistics.to_string(), from.to_string(), to.to_string());
+        self.distances.get(&key).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Goal {
+    Cheapest,
+    Fastest,
+}
+impl Goal {
+    // Главный критерий сравнения, второй разрешает равенство
+    fn key(&self, estimate: &DeliveryEstimate) -> (u64, u64) {
+        let hours = estimate.hours as u64;
+        match self {
+            Goal::Cheapest => (estimate.cost, hours),
+            Goal::Fastest => (hours, estimate.cost),
+        }
+    }
+    fn compare(
+        &self,
+        chosen: &DeliveryEstimate,
+        other: &DeliveryEstimate,
+    ) -> String {
+        match self {
+            Goal::Cheapest => {
+                format!("{} against {}", money(chosen.cost), money(other.cost))
+            }
+            Goal::Fastest => {
+                format!("{} h against {} h", chosen.hours, other.hours)
+            }
+        }
+    }
+}
+
+fn money(cents: u64) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+/*
+ * Выбранный план и объяснение выбора: оценки всех логистик, у которых есть
+ * маршрут, причины отказа от остальных и сравнение с ближайшей альтернативой
+ */
+struct Plan {
+    logistics: String,
+    estimate: DeliveryEstimate,
+    explanation: Vec<String>,
+}
+
+#[derive(Debug)]
+enum PlanError {
+    NoRoute { origin: String, destination: String },
+}
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanError::NoRoute {
+                origin,
+                destination,
+            } => write!(f, "no route from {} to {}", origin, destination),
+        }
+    }
+}
+
+struct Planner<'a> {
+    registry: &'a LogisticsRegistry,
+    routes: &'a Routes,
+}
+impl Planner<'_> {
+    fn plan(&self, shipment: &Shipment, goal: Goal) -> Result<Plan, PlanError> {
+        let mut explanation = vec![];
+        let mut candidates = vec![];
+        for (name, logistics) in self.registry.iter() {
+            let distance = self.routes.distance(
+                name,
+                &shipment.origin,
+                &shipment.destination,
+            );
+            match distance {
+                Some(km) => {
+                    // Фабричный метод выбирает транспорт для логистики
+                    match logistics.make_transport().deliver(shipment, km) {
+                        Ok(estimate) => {
+                            explanation.push(format!(
+                                "{}: {} km, {}",
+                                name, km, estimate
+                            ));
+                            candidates.push((name, estimate));
+                        }
+                        Err(invalid) => explanation
+                            .push(format!("{}: {} km, {}", name, km, invalid)),
+                    }
+                }
+                None => explanation.push(format!("{}: no route", name)),
+            }
+        }
+        candidates.sort_by_key(|(_, estimate)| goal.key(estimate));
+        let mut candidates = candidates.into_iter();
+        let (name, estimate) =
+            candidates.next().ok_or_else(|| PlanError::NoRoute {
+                origin: shipment.origin.clone(),
+                destination: shipment.destination.clone(),
+            })?;
+        explanation.push(match candidates.next() {
+            Some((other, other_estimate)) => format!(
+                "chose {} as {:?}: {} by {}",
+                name,
+                goal,
+                goal.compare(&estimate, &other_estimate),
+                other
+            ),
+            None => format!("chose {}: the only logistics with a route", name),
+        });
+        Ok(Plan {
+            logistics: name.to_string(),
+            estimate,
+            explanation,
+        })
+    }
+}
+
+// Логистика, которую добавляет сторонний код
+struct Plane {}
+impl Transport for Plane {
+    fn name(&self) -> &'static str {
+        "plane"
+    }
+    fn capacity_kg(&self) -> u32 {
+        5_000
+    }
+    fn speed_kmh(&self) -> u32 {
+        700
+    }
+    fn cost_per_km(&self) -> u32 {
+        2_000
+    }
+    fn handling_hours(&self) -> u32 {
+        4
+    }
+}
+struct AirLogistics {}
+impl Logistics for AirLogistics {
+    fn make_transport(&self) -> Box<dyn Transport> {
+        Box::new(Plane {})
+    }
+}
+
+// Сторонний транспорт с незаполненными характеристиками
+struct Drone {}
+impl Transport for Drone {
+    fn name(&self) -> &'static str {
+        "drone"
+    }
+    fn capacity_kg(&self) -> u32 {
+        0
+    }
+    fn speed_kmh(&self) -> u32 {
+        80
+    }
+    fn cost_per_km(&self) -> u32 {
+        50
+    }
+}
+struct DroneLogistics {}
+impl Logistics for DroneLogistics {
+    fn make_transport(&self) -> Box<dyn Transport> {
+        Box::new(Drone {})
+    }
+}
+
 fn main() {
-    let logistic_list: Vec<Box<dyn Logistics>> = vec![
-        Box::new(RoadLogistics::new()),
-        Box::new(SeaLogistics::new()),
+    let mut registry = LogisticsRegistry::with_builtin();
+    registry.register("air", AirLogistics {});
+    registry.register("drone", DroneLogistics {});
+    let mut routes = Routes::new();
+    routes.add("road", "Hamburg", "Oslo", 1100);
+    routes.add("sea", "Hamburg", "Oslo", 900);
+    routes.add("air", "Hamburg", "Oslo", 900);
+    routes.add("drone", "Hamburg", "Oslo", 900);
+    routes.add("road", "Hamburg", "Vienna", 940);
+    let planner = Planner {
+        registry: &registry,
+        routes: &routes,
+    };
+
+    let shipments = [
+        ("Furniture", 1_200, "Hamburg", "Oslo"),
+        ("Steel beams", 15_000, "Hamburg", "Oslo"),
+        ("Books", 300, "Vienna", "Hamburg"),
+        ("Wine", 500, "Oslo", "Vienna"),
     ];
-    for logistics in logistic_list {
-        let transport = logistics.make_transport();
-        transport.deliver();
+    for (description, weight_kg, origin, destination) in shipments {
+        let shipment = Shipment {
+            description: description.to_string(),
+            weight_kg,
+            origin: origin.to_string(),
+            destination: destination.to_string(),
+        };
+        for goal in [Goal::Cheapest, Goal::Fastest] {
+            println!(
+                "{} ({} kg) {} -> {}, {:?}:",
+                shipment.description,
+                shipment.weight_kg,
+                shipment.origin,
+                shipment.destination,
+                goal
+            );
+            match planner.plan(&shipment, goal) {
+                Ok(plan) => {
+                    for line in &plan.explanation {
+                        println!("  {}", line);
+                    }
+                    println!("  => {} {}", plan.logistics, plan.estimate);
+                }
+                Err(err) => println!("  Error: {}", err),
+            }
+        }
+    }
+    if let Err(err) = registry.get("rail") {
+        println!("Error: {}", err);
     }
 }
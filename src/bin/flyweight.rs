@@ -10,9 +10,124 @@
 /*
 * TreeExtendData занимает много памяти, будем кешировать это поле
  */
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    env,
+    hash::{Hash, Hasher},
+    hint::black_box,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LockResult, PoisonError, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+/*
+ * Блокировка отравляется, если поток упал, держа ее. Конструктор значения
+ * вызывается вне блокировки, под ней упасть может разве что хеширование
+ * ключа, а карта при этом остается целой, поэтому работаем с ней как есть
+ */
+fn recover<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+/*
+ * Потокобезопасный кеш легковесов. Кеш хранит только слабые ссылки, поэтому
+ * данные живут, пока на них ссылается хоть кто-то снаружи. Записи с умершими
+ * значениями вычищаются при росте кеша или явным вызовом purge
+ */
+struct Interner<K, V> {
+    map: RwLock<HashMap<K, Weak<V>>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct InternerStats {
+    entries: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl InternerStats {
+    fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
+
+impl<K: Hash + Eq, V> Interner<K, V> {
+    fn new() -> Self {
+        Interner {
+            map: Default::default(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+    /*
+     * Поиск идет по заимствованному ключу, ключ-владелец создается
+     * только при вставке нового значения
+     */
+    fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        recover(self.map.read()).get(key)?.upgrade()
+    }
+    /*
+     * Значение создается без блокировки: медленный конструктор не держит
+     * остальные потоки, а его паника не отравляет кеш. Если за это время
+     * значение успел вставить другой поток, возвращаем его, а свое выбрасываем
+     */
+    fn get_or_insert_with<Q>(&self, key: &Q, make: impl FnOnce() -> V) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(value) = self.get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return value;
+        }
+        let value = Arc::new(make());
+        let mut map = recover(self.map.write());
+        if let Some(winner) = map.get(key).and_then(Weak::upgrade) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return winner;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if map.len() == map.capacity() {
+            map.retain(|_, value| value.strong_count() > 0);
+        }
+        map.insert(key.to_owned(), Arc::downgrade(&value));
+        value
+    }
+    // Удаляет записи, на значения которых больше никто не ссылается
+    fn purge(&self) -> usize {
+        let mut map = recover(self.map.write());
+        let before = map.len();
+        map.retain(|_, value| value.strong_count() > 0);
+        before - map.len()
+    }
+    fn len(&self) -> usize {
+        recover(self.map.read()).len()
+    }
+    fn stats(&self) -> InternerStats {
+        InternerStats {
+            entries: self.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 enum Color {
     Red,
     Green,
@@ -24,61 +139,525 @@ struct TreeExtendData {
     texture_name: String,
     texture: Vec<u8>,
 }
+impl TreeExtendData {
+    // Сколько памяти занимает одна копия данных вместе с содержимым в куче
+    fn size_in_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self.texture_name.capacity()
+            + self.texture.capacity()
+    }
+}
 #[allow(unused)]
 struct Tree {
     x: f32,
     y: f32,
-    data: Rc<TreeExtendData>,
+    data: Arc<TreeExtendData>,
+}
+
+// Без ветвлений: & вместо &&, чтобы циклы по координатам не спотыкались о предсказания
+fn in_rect(point: (f32, f32), min: (f32, f32), max: (f32, f32)) -> bool {
+    (min.0 <= point.0)
+        & (point.0 <= max.0)
+        & (min.1 <= point.1)
+        & (point.1 <= max.1)
+}
+
+fn distance2(point: (f32, f32), tree: &Tree) -> f32 {
+    (tree.x - point.0).powi(2) + (tree.y - point.1).powi(2)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct TreeId(usize);
+
+/*
+ * Пространственный индекс: плоскость поделена на квадратные ячейки, в каждой
+ * ячейке список деревьев. Индекс хранит только TreeId, сами деревья и их
+ * легковесы живут в Forest, поэтому данные не дублируются
+ */
+struct GridIndex {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<TreeId>>,
+    // Рамка занятых ячеек. При удалении не сужается, поэтому может быть шире
+    bounds: Option<((i32, i32), (i32, i32))>,
 }
+
+impl GridIndex {
+    fn new(cell_size: f32) -> Self {
+        GridIndex {
+            cell_size,
+            cells: Default::default(),
+            bounds: None,
+        }
+    }
+    // Приведение f32 к i32 насыщается, поэтому дальние точки попадают в крайние ячейки
+    fn cell(&self, x: f32, y: f32) -> (i32, i32) {
+        (
+            (x / self.cell_size).floor() as i32,
+            (y / self.cell_size).floor() as i32,
+        )
+    }
+    fn insert(&mut self, id: TreeId, x: f32, y: f32) {
+        let cell = self.cell(x, y);
+        self.cells.entry(cell).or_default().push(id);
+        let (min, max) = self.bounds.get_or_insert((cell, cell));
+        *min = (min.0.min(cell.0), min.1.min(cell.1));
+        *max = (max.0.max(cell.0), max.1.max(cell.1));
+    }
+    fn remove(&mut self, id: TreeId, x: f32, y: f32) {
+        let cell = self.cell(x, y);
+        if let Some(ids) = self.cells.get_mut(&cell) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+    /*
+     * Деревья из всех ячеек, пересекающих прямоугольник. Диапазон ячеек
+     * обрезается рамкой занятых ячеек. Если и после этого ячеек в нем больше,
+     * чем занятых, дешевле перебрать занятые ячейки и отфильтровать их
+     */
+    fn candidates(&self, min: (f32, f32), max: (f32, f32)) -> Vec<TreeId> {
+        let Some((low, high)) = self.bounds else {
+            return vec![];
+        };
+        let (min_x, min_y) = self.cell(min.0, min.1);
+        let (max_x, max_y) = self.cell(max.0, max.1);
+        let (min_x, min_y) = (min_x.max(low.0), min_y.max(low.1));
+        let (max_x, max_y) = (max_x.min(high.0), max_y.min(high.1));
+        if min_x > max_x || min_y > max_y {
+            return vec![];
+        }
+        let area = (max_x as i64 - min_x as i64 + 1)
+            * (max_y as i64 - min_y as i64 + 1);
+        if area > self.occupied() as i64 {
+            return self
+                .cells
+                .iter()
+                .filter(|((x, y), _)| {
+                    (min_x..=max_x).contains(x) && (min_y..=max_y).contains(y)
+                })
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+        }
+        (min_x..=max_x)
+            .flat_map(|x| (min_y..=max_y).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect()
+    }
+    /*
+     * Деревья из ячеек на границе квадрата радиуса ring вокруг center, это
+     * 8 * ring ячеек. Координаты считаются в i64, ячейки за пределами i32
+     * пропускаются
+     */
+    fn ring(&self, center: (i32, i32), ring: i64) -> Vec<TreeId> {
+        let (cx, cy) = (center.0 as i64, center.1 as i64);
+        let mut border = vec![];
+        if ring == 0 {
+            border.push((cx, cy));
+        }
+        for d in -ring..=ring {
+            if ring > 0 {
+                border.push((cx + d, cy - ring));
+                border.push((cx + d, cy + ring));
+            }
+            if d.abs() < ring {
+                border.push((cx - ring, cy + d));
+                border.push((cx + ring, cy + d));
+            }
+        }
+        border
+            .into_iter()
+            .filter_map(|(x, y)| {
+                Some((i32::try_from(x).ok()?, i32::try_from(y).ok()?))
+            })
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect()
+    }
+    // Деревья из всех занятых ячеек не ближе ring колец к center
+    fn beyond(&self, center: (i32, i32), ring: i64) -> Vec<TreeId> {
+        self.cells
+            .iter()
+            .filter(|(cell, _)| chebyshev(center, **cell) >= ring)
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect()
+    }
+    fn occupied(&self) -> usize {
+        self.cells.len()
+    }
+    // Номер кольца вокруг center, за которым занятых ячеек уже нет
+    fn max_ring(&self, center: (i32, i32)) -> i64 {
+        self.bounds.map_or(0, |(min, max)| {
+            chebyshev(center, min).max(chebyshev(center, max))
+        })
+    }
+}
+
+// Расстояние между ячейками в кольцах, в i64, чтобы не переполниться
+fn chebyshev(a: (i32, i32), b: (i32, i32)) -> i64 {
+    let dx = (a.0 as i64 - b.0 as i64).abs();
+    let dy = (a.1 as i64 - b.1 as i64).abs();
+    dx.max(dy)
+}
+
+/*
+ * Слот с None остается после удаления дерева и переиспользуется при следующем
+ * добавлении, так TreeId остаются стабильными
+ */
 struct Forest {
-    trees: Vec<Tree>,
+    trees: Vec<Option<Tree>>,
+    free: Vec<TreeId>,
+    index: GridIndex,
 }
 impl Forest {
     fn new() -> Self {
-        Forest { trees: Vec::new() }
+        Forest::with_cell_size(100.0)
     }
-    fn add(&mut self, tree: Tree) {
-        self.trees.push(tree);
+    fn with_cell_size(cell_size: f32) -> Self {
+        Forest {
+            trees: Vec::new(),
+            free: Vec::new(),
+            index: GridIndex::new(cell_size),
+        }
+    }
+    fn add(&mut self, tree: Tree) -> TreeId {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.trees.push(None);
+                TreeId(self.trees.len() - 1)
+            }
+        };
+        self.index.insert(id, tree.x, tree.y);
+        self.trees[id.0] = Some(tree);
+        id
+    }
+    fn remove(&mut self, id: TreeId) -> Option<Tree> {
+        let tree = self.trees.get_mut(id.0)?.take()?;
+        self.index.remove(id, tree.x, tree.y);
+        self.free.push(id);
+        Some(tree)
+    }
+    fn get(&self, id: TreeId) -> Option<&Tree> {
+        self.trees.get(id.0)?.as_ref()
+    }
+    fn len(&self) -> usize {
+        self.trees.len() - self.free.len()
+    }
+    fn iter(&self) -> impl Iterator<Item = &Tree> + '_ {
+        self.trees.iter().flatten()
+    }
+    fn in_rect(
+        &self,
+        min: (f32, f32),
+        max: (f32, f32),
+    ) -> impl Iterator<Item = &Tree> + '_ {
+        self.index
+            .candidates(min, max)
+            .into_iter()
+            .filter_map(|id| self.get(id))
+            .filter(move |tree| in_rect((tree.x, tree.y), min, max))
+    }
+    fn in_radius(
+        &self,
+        center: (f32, f32),
+        radius: f32,
+    ) -> impl Iterator<Item = &Tree> + '_ {
+        let min = (center.0 - radius, center.1 - radius);
+        let max = (center.0 + radius, center.1 + radius);
+        self.in_rect(min, max)
+            .filter(move |tree| distance2(center, tree) <= radius * radius)
+    }
+    /*
+     * Обходим кольца ячеек от центра наружу. Все деревья в кольце ring + 1
+     * и дальше лежат не ближе ring * cell_size, поэтому, когда лучшее
+     * найденное расстояние меньше этой границы, искать дальше незачем.
+     * За рамкой занятых ячеек колец нет. Если кольцо больше, чем всего
+     * занятых ячеек, дешевле один раз перебрать оставшиеся ячейки целиком
+     */
+    fn nearest(&self, point: (f32, f32)) -> Option<(TreeId, &Tree)> {
+        let center = self.index.cell(point.0, point.1);
+        let max_ring = self.index.max_ring(center);
+        let mut best: Option<(TreeId, f32)> = None;
+        let mut seen = 0;
+        let mut ring = 0;
+        while seen < self.len() && ring <= max_ring {
+            let linear = 8 * ring > self.index.occupied() as i64;
+            let ids = if linear {
+                self.index.beyond(center, ring)
+            } else {
+                self.index.ring(center, ring)
+            };
+            for id in ids {
+                seen += 1;
+                let distance = distance2(point, self.get(id)?);
+                if best.is_none_or(|(_, best)| distance < best) {
+                    best = Some((id, distance));
+                }
+            }
+            if linear {
+                break;
+            }
+            let bound = ring as f32 * self.index.cell_size;
+            if best.is_some_and(|(_, best)| best <= bound * bound) {
+                break;
+            }
+            ring += 1;
+        }
+        best.map(|(id, _)| (id, self.get(id).unwrap()))
+    }
+    fn stats(&self) -> ForestStats {
+        let mut usage: HashMap<
+            *const TreeExtendData,
+            (&TreeExtendData, usize),
+        > = HashMap::new();
+        for tree in self.iter() {
+            usage
+                .entry(Arc::as_ptr(&tree.data))
+                .or_insert((&tree.data, 0))
+                .1 += 1;
+        }
+        let mut shared_bytes = self.len() * size_of::<Tree>();
+        let mut separate_bytes = 0;
+        let mut references = vec![];
+        for (data, count) in usage.into_values() {
+            // Arc хранит рядом с данными два счетчика ссылок
+            shared_bytes += data.size_in_bytes() + 2 * size_of::<usize>();
+            separate_bytes +=
+                count * (2 * size_of::<f32>() + data.size_in_bytes());
+            references.push((data.color, data.texture_name.clone(), count));
+        }
+        references.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+        ForestStats {
+            trees: self.len(),
+            references,
+            shared_bytes,
+            separate_bytes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ForestStats {
+    trees: usize,
+    // Уникальные данные и сколько деревьев на них ссылаются, по убыванию
+    references: Vec<(Color, String, usize)>,
+    // Память с легковесами и если бы каждое дерево хранило свою копию данных
+    shared_bytes: usize,
+    separate_bytes: usize,
+}
+
+impl ForestStats {
+    fn unique_data(&self) -> usize {
+        self.references.len()
+    }
+    fn bytes_saved(&self) -> isize {
+        self.separate_bytes as isize - self.shared_bytes as isize
+    }
+}
+
+/*
+ * Ключ кеша владеет строкой, а искать хочется по (Color, &str) без
+ * выделения памяти. Обе формы ключа приводятся к dyn TextureKey, у которого
+ * одинаковые Hash и Eq
+ */
+#[derive(PartialEq, Eq)]
+struct TreeKey {
+    color: Color,
+    texture_name: String,
+}
+
+trait TextureKey {
+    fn key(&self) -> (Color, &str);
+}
+impl TextureKey for TreeKey {
+    fn key(&self) -> (Color, &str) {
+        (self.color, &self.texture_name)
+    }
+}
+impl TextureKey for (Color, &str) {
+    fn key(&self) -> (Color, &str) {
+        *self
+    }
+}
+impl Hash for TreeKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+impl Hash for dyn TextureKey + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+impl PartialEq for dyn TextureKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl Eq for dyn TextureKey + '_ {}
+impl<'a> Borrow<dyn TextureKey + 'a> for TreeKey {
+    fn borrow(&self) -> &(dyn TextureKey + 'a) {
+        self
+    }
+}
+impl ToOwned for dyn TextureKey + '_ {
+    type Owned = TreeKey;
+    fn to_owned(&self) -> TreeKey {
+        let (color, texture_name) = self.key();
+        TreeKey {
+            color,
+            texture_name: texture_name.to_string(),
+        }
     }
 }
 
 struct TreeFactory {
-    cache: HashMap<(Color, String), Rc<TreeExtendData>>,
+    cache: Interner<TreeKey, TreeExtendData>,
 }
 
 impl TreeFactory {
     fn new() -> Self {
         TreeFactory {
-            cache: Default::default(),
+            cache: Interner::new(),
         }
     }
     fn make(
-        &mut self,
+        &self,
         color: Color,
         texture_name: &str,
         texture: &[u8],
         x: f32,
         y: f32,
     ) -> Tree {
-        let key = (color, texture_name.to_string());
-        if !self.cache.contains_key(&key) {
-            let data = TreeExtendData {
-                color,
-                texture_name: texture_name.to_string(),
-                texture: texture.to_vec(),
-            };
-            self.cache.insert(key.clone(), Rc::new(data));
+        let key: &dyn TextureKey = &(color, texture_name);
+        let data = self.cache.get_or_insert_with(key, || TreeExtendData {
+            color,
+            texture_name: texture_name.to_string(),
+            texture: texture.to_vec(),
+        });
+        Tree { x, y, data }
+    }
+}
+
+/*
+ * Та же коллекция деревьев, но в виде структуры массивов: координаты лежат
+ * в отдельных плотных массивах, а вместо указателя на легковес хранится
+ * номер u32 в таблице легковесов. При обходе по координатам в кеш процессора
+ * попадают только нужные данные, и на каждое дерево уходит 12 байт вместо 16
+ */
+struct CompactForest {
+    xs: Vec<f32>,
+    ys: Vec<f32>,
+    kinds: Vec<u32>,
+    table: Vec<Arc<TreeExtendData>>,
+    table_index: HashMap<*const TreeExtendData, u32>,
+}
+
+// Дерево при обходе CompactForest, собранное из параллельных массивов
+struct TreeView<'a> {
+    x: f32,
+    y: f32,
+    data: &'a TreeExtendData,
+}
+
+impl CompactForest {
+    fn new() -> Self {
+        CompactForest {
+            xs: vec![],
+            ys: vec![],
+            kinds: vec![],
+            table: vec![],
+            table_index: Default::default(),
+        }
+    }
+    fn add(&mut self, tree: Tree) {
+        let table = &mut self.table;
+        let kind = *self
+            .table_index
+            .entry(Arc::as_ptr(&tree.data))
+            .or_insert_with(|| {
+                table.push(tree.data.clone());
+                (table.len() - 1) as u32
+            });
+        self.xs.push(tree.x);
+        self.ys.push(tree.y);
+        self.kinds.push(kind);
+    }
+    fn len(&self) -> usize {
+        self.xs.len()
+    }
+    fn get(&self, index: usize) -> TreeView<'_> {
+        TreeView {
+            x: self.xs[index],
+            y: self.ys[index],
+            data: &self.table[self.kinds[index] as usize],
         }
-        Tree {
-            x,
-            y,
-            data: self.cache.get(&key).unwrap().clone(),
+    }
+    fn iter(&self) -> impl Iterator<Item = TreeView<'_>> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+    /*
+     * Деревья внутри прямоугольника. Сначала без ветвлений проходим только
+     * массивы координат и собираем номера попавших деревьев, и только для
+     * них читаем kinds и таблицу легковесов
+     */
+    fn in_rect(
+        &self,
+        min: (f32, f32),
+        max: (f32, f32),
+    ) -> impl Iterator<Item = TreeView<'_>> + '_ {
+        const CHUNK: usize = 256;
+        let mut hits: Vec<u32> = vec![];
+        let mut chunk_hits = [0u32; CHUNK];
+        let ys = self.ys.chunks(CHUNK);
+        for (chunk, (xs, ys)) in self.xs.chunks(CHUNK).zip(ys).enumerate() {
+            let mut count = 0;
+            for (i, (x, y)) in xs.iter().zip(ys).enumerate() {
+                // Номер пишется всегда, а счетчик растет, только если дерево внутри
+                chunk_hits[count] = (chunk * CHUNK + i) as u32;
+                count += in_rect((*x, *y), min, max) as usize;
+            }
+            hits.extend_from_slice(&chunk_hits[..count]);
         }
+        hits.into_iter().map(|index| self.get(index as usize))
+    }
+    // Для подсчета хватает одних координат, легковесы не нужны вовсе
+    fn count_in_rect(&self, min: (f32, f32), max: (f32, f32)) -> usize {
+        self.xs
+            .iter()
+            .zip(&self.ys)
+            .filter(|(x, y)| in_rect((**x, **y), min, max))
+            .count()
     }
 }
+
+// Простой генератор псевдослучайных координат, чтобы замер был воспроизводимым
+fn next_coord(seed: &mut u32) -> f32 {
+    *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+    (*seed >> 8) as f32 / (1 << 24) as f32 * 1000.0
+}
+
+// Лучшее время из нескольких запусков, чтобы сгладить случайные помехи
+fn bench(name: &str, f: impl Fn() -> usize) {
+    let mut best = None;
+    let mut found = 0;
+    for _ in 0..5 {
+        let start = Instant::now();
+        found = black_box(f());
+        let elapsed = start.elapsed();
+        best = Some(best.map_or(elapsed, |best: Duration| best.min(elapsed)));
+    }
+    println!("{}: {} trees in {:?}", name, found, best.unwrap());
+}
+
 fn main() {
     let mut forest = Forest::new();
-    let mut factory = TreeFactory::new();
+    let factory = TreeFactory::new();
     let tree1 = factory.make(Color::Red, "a", &vec![0; 1024], 10.0, 10.0);
     let tree2 = factory.make(Color::Green, "b", &vec![0; 1024], 20.0, 20.0);
     let tree3 = factory.make(Color::Yellow, "c", &vec![0; 1024], 30.0, 30.0);
@@ -90,5 +669,201 @@ fn main() {
     forest.add(tree3);
     forest.add(tree4);
     forest.add(tree5);
-    forest.add(tree6);
+    let id = forest.add(tree6);
+    let near = factory.make(Color::Green, "b", &[0; 1024], -28.0, -29.0);
+    let near = forest.add(near);
+    for tree in forest.in_radius((-25.0, -25.0), 10.0) {
+        println!("{:?} tree at ({}, {})", tree.data.color, tree.x, tree.y);
+    }
+    println!("Nearest: {:?}", forest.nearest((-31.0, -31.0)).map(|n| n.0));
+    forest.remove(id);
+    println!("Nearest: {:?}", forest.nearest((-31.0, -31.0)).map(|n| n.0));
+    forest.remove(near);
+    let stats = forest.stats();
+    println!(
+        "Trees: {}, unique data: {}, bytes saved: {}",
+        stats.trees,
+        stats.unique_data(),
+        stats.bytes_saved()
+    );
+    for (color, texture_name, count) in &stats.references {
+        println!("  {:?} '{}': {} trees", color, texture_name, count);
+    }
+    let cache = factory.cache.stats();
+    println!(
+        "Cache entries: {}, hits: {}, misses: {}, hit ratio: {:.2}",
+        cache.entries,
+        cache.hits,
+        cache.misses,
+        cache.hit_ratio()
+    );
+
+    // Рабочие потоки берут текстуры из общего кеша
+    let factory = Arc::new(factory);
+    let workers: Vec<_> = (0..4)
+        .map(|i| {
+            let factory = factory.clone();
+            thread::spawn(move || {
+                let color = if i % 2 == 0 { Color::Red } else { Color::Green };
+                factory.make(color, "shared", &[0; 1024], i as f32, 0.0)
+            })
+        })
+        .collect();
+    let trees: Vec<Tree> =
+        workers.into_iter().map(|w| w.join().unwrap()).collect();
+    println!(
+        "Same texture in threads: {}",
+        Arc::ptr_eq(&trees[0].data, &trees[2].data)
+    );
+    println!("Cached textures: {}", factory.cache.len());
+
+    // Текстуры, на которые больше нет ссылок, вычищаются из кеша
+    drop(trees);
+    println!("Purged: {}", factory.cache.purge());
+    println!("Cached textures: {}", factory.cache.len());
+    drop(forest);
+    println!("Purged: {}", factory.cache.purge());
+    let key: &dyn TextureKey = &(Color::Red, "a");
+    println!("Red 'a' is cached: {}", factory.cache.get(key).is_some());
+
+    let mut compact = CompactForest::new();
+    for tree in [
+        factory.make(Color::Red, "a", &[0; 1024], 1.0, 1.0),
+        factory.make(Color::Green, "b", &[0; 1024], 5.0, 5.0),
+        factory.make(Color::Red, "a", &[0; 1024], 9.0, 9.0),
+    ] {
+        compact.add(tree);
+    }
+    for tree in compact.in_rect((0.0, 0.0), (6.0, 6.0)) {
+        println!("{:?} tree at ({}, {})", tree.data.color, tree.x, tree.y);
+    }
+
+    /*
+     * Сравнение раскладок на миллионах деревьев запускается только по
+     * запросу, цифры имеют смысл в release-сборке:
+     * FOREST_BENCH=1 cargo run --release --bin flyweight
+     */
+    if env::var_os("FOREST_BENCH").is_none() {
+        return;
+    }
+    if cfg!(debug_assertions) {
+        println!("Debug build: timings below are not representative");
+    }
+    const TREES: usize = 2_000_000;
+    let kinds = [
+        factory.make(Color::Red, "oak", &[0; 1024], 0.0, 0.0).data,
+        factory
+            .make(Color::Green, "pine", &[0; 1024], 0.0, 0.0)
+            .data,
+        factory
+            .make(Color::Yellow, "birch", &[0; 1024], 0.0, 0.0)
+            .data,
+    ];
+    let mut forest = Forest::new();
+    let mut compact = CompactForest::new();
+    let mut seed = 42;
+    for i in 0..TREES {
+        let tree = Tree {
+            x: next_coord(&mut seed),
+            y: next_coord(&mut seed),
+            data: kinds[i % kinds.len()].clone(),
+        };
+        compact.add(Tree {
+            x: tree.x,
+            y: tree.y,
+            data: tree.data.clone(),
+        });
+        forest.add(tree);
+    }
+    let (min, max) = black_box(((100.0, 100.0), (300.0, 300.0)));
+    // Замеры обращаются к данным легковеса, иначе компилятор пропустит чтение
+    bench("Vec<Tree> trees in rect", || {
+        forest
+            .iter()
+            .filter(|t| in_rect((t.x, t.y), min, max))
+            .count()
+    });
+    bench("CompactForest trees in rect", || {
+        compact.count_in_rect(min, max)
+    });
+    bench("Vec<Tree> red trees in rect", || {
+        forest
+            .iter()
+            .filter(|t| in_rect((t.x, t.y), min, max))
+            .filter(|t| t.data.color == Color::Red)
+            .count()
+    });
+    bench("CompactForest red trees in rect", || {
+        compact
+            .in_rect(min, max)
+            .filter(|t| t.data.color == Color::Red)
+            .count()
+    });
+    bench("Forest grid index red trees in rect", || {
+        forest
+            .in_rect(min, max)
+            .filter(|t| t.data.color == Color::Red)
+            .count()
+    });
+    bench("Vec<Tree> red trees", || {
+        forest.iter().filter(|t| t.data.color == Color::Red).count()
+    });
+    bench("CompactForest red trees", || {
+        compact
+            .iter()
+            .filter(|t| t.data.color == Color::Red)
+            .count()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(factory: &TreeFactory, x: f32, y: f32) -> Tree {
+        factory.make(Color::Red, "a", &[0; 16], x, y)
+    }
+
+    // Конструктор может сам обращаться к кешу: блокировка на это время снята
+    #[test]
+    fn make_runs_without_lock() {
+        let cache: Interner<String, u32> = Interner::new();
+        let outer = cache.get_or_insert_with("outer", || {
+            *cache.get_or_insert_with("inner", || 1) + 1
+        });
+        assert_eq!(*outer, 2);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn panic_in_make_does_not_poison_cache() {
+        let cache: Interner<String, u32> = Interner::new();
+        let failed = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    cache.get_or_insert_with("a", || panic!("no texture"))
+                })
+                .join()
+        });
+        assert!(failed.is_err());
+        assert_eq!(*cache.get_or_insert_with("a", || 1), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    // Огромный прямоугольник не должен обходить пустые ячейки между углами
+    #[test]
+    fn huge_rect_scans_only_occupied_cells() {
+        let factory = TreeFactory::new();
+        let mut forest = Forest::new();
+        forest.add(tree(&factory, 10.0, 10.0));
+        assert_eq!(forest.in_rect((-1e6, -1e6), (1e6, 1e6)).count(), 1);
+        forest.add(tree(&factory, -1e9, -1e9));
+        forest.add(tree(&factory, 1e9, 1e9));
+        let min = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+        let max = (f32::INFINITY, f32::INFINITY);
+        assert_eq!(forest.in_rect(min, max).count(), 3);
+        assert_eq!(forest.in_rect((-1e6, -1e6), (1e6, 1e6)).count(), 1);
+        assert_eq!(forest.in_radius((0.0, 0.0), f32::INFINITY).count(), 3);
+        assert_eq!(forest.in_rect((1e6, 1e6), (-1e6, -1e6)).count(), 0);
+    }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, fs, hash::Hash, path::Path, rc::Rc};
 
 /**
  * Прототип (Prototype) — это порождающий паттерн проектирования, который позволяет копировать объекты, не вдаваясь в подробности их реализации.
@@ -11,42 +11,327 @@ use std::collections::HashMap;
  * классы, чтобы иметь возможность легко порождать объекты с определённой конфигурацией
  **/
 
+/*
+ * Контракт копирования:
+ * - Clone копирует собственные поля, а поля за Rc остаются общими для всех
+ *   копий (поверхностная копия). Так все воины отряда делят одно знамя
+ * - DeepClone копирует и общие поля, копия ни с кем ничего не делит
+ */
+trait DeepClone {
+    fn deep_clone(&self) -> Self;
+}
+
+#[derive(Debug, Clone)]
+struct Banner {
+    title: String,
+}
+
+#[derive(Debug, Clone)]
 #[allow(unused)]
 struct Warrior {
     name: String,
     force: u32,
+    weapons: Vec<String>,
+    banner: Rc<Banner>,
 }
 
-impl Clone for Warrior {
-    fn clone(&self) -> Self {
+impl DeepClone for Warrior {
+    fn deep_clone(&self) -> Self {
         Warrior {
-            name: self.name.clone(),
-            force: 90,
+            banner: Rc::new(self.banner.as_ref().clone()),
+            ..self.clone()
+        }
+    }
+}
+
+/*
+ * Прототипы разных типов хранятся как Box<dyn Unit>. Clone нельзя сделать
+ * методом трейт-объекта, поэтому копирование идет через clone_box, который
+ * реализуется автоматически для любого юнита с Clone
+ */
+trait Unit: CloneUnit {
+    fn name(&self) -> &str;
+    fn describe(&self) -> String;
+    fn force_mut(&mut self) -> &mut u32;
+}
+
+trait CloneUnit {
+    fn clone_box(&self) -> Box<dyn Unit>;
+}
+
+impl<T: Unit + Clone + 'static> CloneUnit for T {
+    fn clone_box(&self) -> Box<dyn Unit> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Unit> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl Unit for Warrior {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn describe(&self) -> String {
+        format!(
+            "{} (force {}, weapons: {}, banner: {})",
+            self.name,
+            self.force,
+            self.weapons.join(", "),
+            self.banner.title
+        )
+    }
+    fn force_mut(&mut self) -> &mut u32 {
+        &mut self.force
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Archer {
+    name: String,
+    force: u32,
+    range: u32,
+}
+
+impl Unit for Archer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn describe(&self) -> String {
+        format!("{} (force {}, range {})", self.name, self.force, self.range)
+    }
+    fn force_mut(&mut self) -> &mut u32 {
+        &mut self.force
+    }
+}
+
+struct PrototypeRegistry<K, T> {
+    prototypes: HashMap<K, T>,
+}
+
+impl<K: Hash + Eq, T: Clone> PrototypeRegistry<K, T> {
+    fn new() -> Self {
+        PrototypeRegistry {
+            prototypes: HashMap::new(),
+        }
+    }
+    fn register(&mut self, key: K, prototype: T) {
+        self.prototypes.insert(key, prototype);
+    }
+    fn spawn(&self, key: &K) -> Option<T> {
+        self.prototypes.get(key).cloned()
+    }
+    // Копия с изменениями, которые касаются только этого экземпляра
+    fn spawn_with(&self, key: &K, customize: impl FnOnce(&mut T)) -> Option<T> {
+        let mut object = self.spawn(key)?;
+        customize(&mut object);
+        Some(object)
+    }
+    fn spawn_deep(&self, key: &K) -> Option<T>
+    where
+        T: DeepClone,
+    {
+        self.prototypes.get(key).map(DeepClone::deep_clone)
+    }
+}
+
+#[derive(Debug)]
+struct LoadError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// Секция файла шаблонов: [Имя] и следующие за ней строки ключ = значение
+struct Section {
+    line: usize,
+    name: String,
+    fields: HashMap<String, String>,
+}
+
+impl Section {
+    fn error(&self, message: String) -> LoadError {
+        LoadError {
+            line: self.line,
+            message: format!("[{}] {}", self.name, message),
+        }
+    }
+    fn field(&self, key: &str) -> Result<&str, LoadError> {
+        self.fields
+            .get(key)
+            .map(String::as_str)
+            .ok_or_else(|| self.error(format!("missing field '{}'", key)))
+    }
+    fn number(&self, key: &str) -> Result<u32, LoadError> {
+        let value = self.field(key)?;
+        value
+            .parse()
+            .map_err(|_| self.error(format!("'{}' is not a number", value)))
+    }
+}
+
+fn parse_sections(text: &str) -> Result<Vec<Section>, LoadError> {
+    let mut sections: Vec<Section> = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        if let Some(name) =
+            line.strip_prefix('[').and_then(|l| l.strip_suffix(']'))
+        {
+            sections.push(Section {
+                line: index + 1,
+                name: name.trim().to_string(),
+                fields: HashMap::new(),
+            });
+            continue;
+        }
+        let error = |message: &str| LoadError {
+            line: index + 1,
+            message: message.to_string(),
+        };
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected 'key = value'"))?;
+        let section = sections
+            .last_mut()
+            .ok_or_else(|| error("field outside of a section"))?;
+        section
+            .fields
+            .insert(key.trim().to_string(), value.trim().to_string());
+    }
+    Ok(sections)
+}
+
+/*
+ * Заполняет реестр прототипами из текста шаблонов. Воины с одинаковым
+ * знаменем получают одно общее Rc<Banner>
+ */
+fn load_units(
+    text: &str,
+) -> Result<PrototypeRegistry<String, Box<dyn Unit>>, LoadError> {
+    let mut registry = PrototypeRegistry::new();
+    let mut banners: HashMap<String, Rc<Banner>> = HashMap::new();
+    for section in parse_sections(text)? {
+        let unit: Box<dyn Unit> = match section.field("kind")? {
+            "warrior" => {
+                let title = section.field("banner")?;
+                let banner = banners
+                    .entry(title.to_string())
+                    .or_insert_with(|| {
+                        Rc::new(Banner {
+                            title: title.to_string(),
+                        })
+                    })
+                    .clone();
+                Box::new(Warrior {
+                    name: section.name.clone(),
+                    force: section.number("force")?,
+                    weapons: section
+                        .field("weapons")?
+                        .split(',')
+                        .map(|weapon| weapon.trim().to_string())
+                        .collect(),
+                    banner,
+                })
+            }
+            "archer" => Box::new(Archer {
+                name: section.name.clone(),
+                force: section.number("force")?,
+                range: section.number("range")?,
+            }),
+            kind => {
+                return Err(section.error(format!("unknown kind '{}'", kind)))
+            }
+        };
+        registry.register(section.name.clone(), unit);
     }
+    Ok(registry)
+}
+
+fn load_units_file(
+    path: &Path,
+) -> Result<PrototypeRegistry<String, Box<dyn Unit>>, LoadError> {
+    let text = fs::read_to_string(path).map_err(|err| LoadError {
+        line: 0,
+        message: format!("{}: {}", path.display(), err),
+    })?;
+    load_units(&text)
 }
 
 fn main() {
-    let mut warriors_dict = HashMap::new();
-    warriors_dict.insert(
+    let banner = Rc::new(Banner {
+        title: "Lion".to_string(),
+    });
+    let mut registry = PrototypeRegistry::new();
+    registry.register(
         "Knight",
         Warrior {
             name: "Knight".to_string(),
             force: 90,
+            weapons: vec!["sword".to_string()],
+            banner: banner.clone(),
         },
     );
-    warriors_dict.insert(
+    registry.register(
         "Farmer",
         Warrior {
             name: "Farmer".to_string(),
             force: 40,
+            weapons: vec!["pitchfork".to_string()],
+            banner: banner.clone(),
         },
     );
     // Мы можем заранее создать группу объектов как нам нужно, а потом вызывающий код будет их просто клонировать
     // Создадим армию из 5 рыцарей и 5 крестьян
     let mut warriors = vec![];
     for _ in 0..5 {
-        warriors.push(warriors_dict.get("Knight").unwrap().clone());
-        warriors.push(warriors_dict.get("Farmer").unwrap().clone());
+        warriors.push(registry.spawn(&"Knight").unwrap());
+        warriors.push(registry.spawn(&"Farmer").unwrap());
+    }
+    println!("{:?}", warriors[1]);
+
+    let captain = registry
+        .spawn_with(&"Knight", |warrior| {
+            warrior.name = "Captain".to_string();
+            warrior.force += 10;
+            warrior.weapons.push("shield".to_string());
+        })
+        .unwrap();
+    println!("{:?}", captain);
+    // Знамя у поверхностных копий общее, а у глубокой копии свое
+    let mercenary = registry.spawn_deep(&"Knight").unwrap();
+    println!(
+        "Shared banner: {}, mercenary shares banner: {}",
+        Rc::ptr_eq(&captain.banner, &banner),
+        Rc::ptr_eq(&mercenary.banner, &banner)
+    );
+
+    // Шаблоны юнитов редактируются в файле, а не в коде
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/units.ini");
+    let units = load_units_file(&path).unwrap();
+    let mut army: Vec<Box<dyn Unit>> = vec![];
+    for name in ["Knight", "Farmer", "Archer"] {
+        army.push(units.spawn(&name.to_string()).unwrap());
+    }
+    army.push(
+        units
+            .spawn_with(&"Archer".to_string(), |unit| *unit.force_mut() += 5)
+            .unwrap(),
+    );
+    for unit in &army {
+        println!("{}: {}", unit.name(), unit.describe());
+    }
+    if let Err(err) = load_units("[Giant]\nkind = troll\n") {
+        println!("Error: {}", err);
     }
 }
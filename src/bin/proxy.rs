@@ -16,24 +16,293 @@
  * клиентов и управлять их жизненным циклом
  *
 **/
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Permission {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+enum StorageError {
+    PermissionDenied {
+        user: String,
+        permission: Permission,
+    },
+    Io(io::Error),
+    // Ошибка, которую вернул сервер удаленного хранилища
+    Remote(String),
+    Protocol(String),
+    // Ошибка, сохраненная в записи вызовов и воспроизведенная из нее
+    Recorded(String),
+    // Вызов не совпал с тем, что был записан
+    Replay(String),
+    RateLimited,
+    CircuitOpen,
+    // Неудачную запись не удалось откатить, хранилище больше не пишет
+    Poisoned,
+}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::PermissionDenied { user, permission } => {
+                write!(f, "user {} has no {:?} permission", user, permission)
+            }
+            StorageError::Io(err) => write!(f, "io error: {}", err),
+            StorageError::Remote(msg) => write!(f, "remote error: {}", msg),
+            StorageError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            StorageError::Recorded(msg) => write!(f, "recorded error: {}", msg),
+            StorageError::Replay(msg) => write!(f, "replay error: {}", msg),
+            StorageError::RateLimited => write!(f, "rate limit exceeded"),
+            StorageError::CircuitOpen => write!(f, "circuit breaker is open"),
+            StorageError::Poisoned => {
+                write!(f, "storage is poisoned by a failed write")
+            }
+        }
+    }
+}
 
 trait Storage {
-    fn save(&self);
-    fn remove(&self);
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError>;
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError>;
+    fn remove(&mut self, key: &str) -> Result<(), StorageError>;
+    // Все пары, ключ которых начинается с prefix, упорядоченные по ключу
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError>;
 }
 
-struct Database {}
+fn scan(
+    data: &BTreeMap<String, String>,
+    prefix: &str,
+) -> Vec<(String, String)> {
+    data.range(prefix.to_string()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+// Хранилище в памяти
+struct Database {
+    data: BTreeMap<String, String>,
+}
 impl Database {
     fn new() -> Self {
-        Database {}
+        println!("Database connected");
+        Database {
+            data: Default::default(),
+        }
     }
 }
 impl Storage for Database {
-    fn save(&self) {
-        println!("save data");
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        println!("read {} from database", key);
+        Ok(self.data.get(key).cloned())
     }
-    fn remove(&self) {
-        println!("remove data");
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.data.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.data.remove(key);
+        Ok(())
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        Ok(scan(&self.data, prefix))
+    }
+}
+
+/*
+ * Хранилище в файле, в который данные только дописываются. Каждая запись:
+ * [crc32][операция][длина ключа][длина значения][ключ][значение].
+ * После записи вызывается sync_data, поэтому подтвержденная операция
+ * переживает падение процесса. Если процесс упал посреди записи, последняя
+ * запись окажется короче объявленной длины: при открытии такой хвост
+ * отбрасывается, и файл обрезается до последней целой записи. Запись
+ * с неверной контрольной суммой, за которой есть еще данные, это уже
+ * повреждение файла, а не оборванный хвост: открытие завершается ошибкой,
+ * чтобы не стереть молча все целые записи после нее.
+ * Если запись не удалась без падения процесса (например, кончился диск),
+ * файл сразу обрезается до прежней длины, чтобы следующие записи не легли
+ * после оборванной и не пропали при открытии. Если не удалось и это,
+ * хранилище отравляется и отказывается писать дальше
+ */
+const OP_PUT: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const HEADER_LEN: usize = 13;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+struct FileDatabase {
+    file: File,
+    // Длина файла, в которой лежат только целые записи
+    len: u64,
+    poisoned: bool,
+    data: BTreeMap<String, String>,
+}
+impl FileDatabase {
+    fn open(path: &Path) -> Result<Self, StorageError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        let mut data = BTreeMap::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            if Self::is_torn(&buf[offset..]) {
+                println!("Drop {} bytes of broken tail", buf.len() - offset);
+                file.set_len(offset as u64)?;
+                file.sync_data()?;
+                break;
+            }
+            let (op, key, value, len) = Self::decode(&buf[offset..])
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupted record at offset {}", offset),
+                    )
+                })?;
+            match op {
+                OP_PUT => data.insert(key, value),
+                _ => data.remove(&key),
+            };
+            offset += len;
+        }
+        Ok(FileDatabase {
+            file,
+            len: offset as u64,
+            poisoned: false,
+            data,
+        })
+    }
+    fn encode(op: u8, key: &str, value: &str) -> Vec<u8> {
+        let mut body = vec![op];
+        body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.extend_from_slice(value.as_bytes());
+        let mut record = crc32(&body).to_le_bytes().to_vec();
+        record.extend(body);
+        record
+    }
+    // Запись обрывается концом файла раньше, чем кончается ее объявленная длина
+    fn is_torn(buf: &[u8]) -> bool {
+        if buf.len() < HEADER_LEN {
+            return true;
+        }
+        let len = HEADER_LEN as u64
+            + read_u32(buf, 5) as u64
+            + read_u32(buf, 9) as u64;
+        (buf.len() as u64) < len
+    }
+    // Возвращает None, если запись оборвана или повреждена
+    fn decode(buf: &[u8]) -> Option<(u8, String, String, usize)> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let key_len = read_u32(buf, 5) as usize;
+        let value_len = read_u32(buf, 9) as usize;
+        let len = HEADER_LEN.checked_add(key_len)?.checked_add(value_len)?;
+        if buf.len() < len || crc32(&buf[4..len]) != read_u32(buf, 0) {
+            return None;
+        }
+        let op = buf[4];
+        if op != OP_PUT && op != OP_REMOVE {
+            return None;
+        }
+        let key_end = HEADER_LEN + key_len;
+        let key = String::from_utf8(buf[HEADER_LEN..key_end].to_vec()).ok()?;
+        let value = String::from_utf8(buf[key_end..len].to_vec()).ok()?;
+        Some((op, key, value, len))
+    }
+    fn append(
+        &mut self,
+        op: u8,
+        key: &str,
+        value: &str,
+    ) -> Result<(), StorageError> {
+        if self.poisoned {
+            return Err(StorageError::Poisoned);
+        }
+        let record = Self::encode(op, key, value);
+        let written = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data());
+        if let Err(err) = written {
+            let rollback = self
+                .file
+                .set_len(self.len)
+                .and_then(|_| self.file.sync_data());
+            if rollback.is_err() {
+                self.poisoned = true;
+            }
+            return Err(err.into());
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+}
+impl Storage for FileDatabase {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.data.get(key).cloned())
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        // Сначала на диск, потом в память: в памяти только то, что уже записано
+        self.append(OP_PUT, key, value)?;
+        self.data.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.append(OP_REMOVE, key, "")?;
+        self.data.remove(key);
+        Ok(())
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        Ok(scan(&self.data, prefix))
     }
 }
 struct DatabaseProxy {
@@ -45,20 +314,957 @@ impl DatabaseProxy {
     }
 }
 impl Storage for DatabaseProxy {
-    fn save(&self) {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        println!("Do before");
+        let result = self.service.get(key);
+        println!("Do after");
+        result
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
         println!("Do before");
-        self.service.save();
+        let result = self.service.put(key, value);
         println!("Do after");
+        result
     }
-    fn remove(&self) {
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
         println!("Do before");
-        self.service.remove();
+        let result = self.service.remove(key);
         println!("Do after");
+        result
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        println!("Do before");
+        let result = self.service.scan_prefix(prefix);
+        println!("Do after");
+        result
+    }
+}
+
+// Виртуальный прокси: настоящее хранилище создается только при первом обращении
+struct LazyProxy {
+    service: Option<Box<dyn Storage>>,
+    factory: Box<dyn Fn() -> Box<dyn Storage>>,
+}
+impl LazyProxy {
+    fn new(factory: Box<dyn Fn() -> Box<dyn Storage>>) -> Self {
+        LazyProxy {
+            service: None,
+            factory,
+        }
+    }
+    fn service(&mut self) -> &mut dyn Storage {
+        let factory = &self.factory;
+        self.service.get_or_insert_with(|| factory()).as_mut()
+    }
+}
+impl Storage for LazyProxy {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        self.service().get(key)
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.service().put(key, value)
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.service().remove(key)
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        self.service().scan_prefix(prefix)
+    }
+}
+
+/*
+ * Кеширующий прокси: повторные чтения отдаются из памяти. Запись идет сквозь
+ * кеш, поэтому кеш никогда не расходится с хранилищем. Отсутствие ключа тоже
+ * кешируется
+ */
+struct CachingProxy {
+    service: Box<dyn Storage>,
+    cache: HashMap<String, Option<String>>,
+}
+impl CachingProxy {
+    fn new(service: Box<dyn Storage>) -> Self {
+        CachingProxy {
+            service,
+            cache: Default::default(),
+        }
+    }
+}
+impl Storage for CachingProxy {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        if let Some(value) = self.cache.get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.service.get(key)?;
+        self.cache.insert(key.to_string(), value.clone());
+        Ok(value)
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.service.put(key, value)?;
+        self.cache.insert(key.to_string(), Some(value.to_string()));
+        Ok(())
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.service.remove(key)?;
+        self.cache.insert(key.to_string(), None);
+        Ok(())
+    }
+    // Выборки по префиксу не кешируются, их сложно инвалидировать
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        self.service.scan_prefix(prefix)
+    }
+}
+
+struct User {
+    name: String,
+    permissions: Vec<Permission>,
+}
+impl User {
+    fn new(name: &str, permissions: &[Permission]) -> Self {
+        User {
+            name: name.to_string(),
+            permissions: permissions.to_vec(),
+        }
+    }
+}
+
+// Защищающий прокси: пропускает вызов дальше, только если у пользователя есть права
+struct AccessControlProxy {
+    service: Box<dyn Storage>,
+    user: User,
+}
+impl AccessControlProxy {
+    fn new(service: Box<dyn Storage>, user: User) -> Self {
+        AccessControlProxy { service, user }
+    }
+    fn check(&self, permission: Permission) -> Result<(), StorageError> {
+        if self.user.permissions.contains(&permission) {
+            return Ok(());
+        }
+        Err(StorageError::PermissionDenied {
+            user: self.user.name.clone(),
+            permission,
+        })
+    }
+}
+impl Storage for AccessControlProxy {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        self.check(Permission::Read)?;
+        self.service.get(key)
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.check(Permission::Write)?;
+        self.service.put(key, value)
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.check(Permission::Write)?;
+        self.service.remove(key)
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        self.check(Permission::Read)?;
+        self.service.scan_prefix(prefix)
+    }
+}
+
+/*
+ * Удаленный прокси: хранилище живет в другом процессе, а прокси пересылает
+ * вызовы через unix socket. Формат кадра:
+ * [длина u32][id запроса u32][тип u8][данные], числа в little endian,
+ * строки передаются как [длина u32][байты utf-8]. На каждый запрос сервер
+ * отвечает кадром с тем же id: FRAME_OK с результатом или FRAME_ERROR
+ * с ошибкой: [вид ошибки u8][поля]. По виду клиент восстанавливает тот же
+ * вариант StorageError, иначе отказ в доступе на сервере выглядел бы
+ * для клиента как отказ хранилища
+ */
+const FRAME_GET: u8 = 1;
+const FRAME_PUT: u8 = 2;
+const FRAME_REMOVE: u8 = 3;
+const FRAME_SCAN: u8 = 4;
+const FRAME_OK: u8 = 10;
+const FRAME_ERROR: u8 = 11;
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+fn put_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_bytes(buf, value.as_bytes());
+}
+
+fn write_frame(
+    stream: &mut impl Write,
+    id: u32,
+    kind: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = ((payload.len() + 5) as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&id.to_le_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+// Возвращает None, если собеседник закрыл соединение между кадрами
+fn read_frame(
+    stream: &mut impl Read,
+) -> Result<Option<(u32, u8, Vec<u8>)>, StorageError> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if !(5..=MAX_FRAME_LEN).contains(&len) {
+        return Err(StorageError::Protocol(format!(
+            "bad frame length {}",
+            len
+        )));
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame)?;
+    let id = read_u32(&frame, 0);
+    Ok(Some((id, frame[4], frame[5..].to_vec())))
+}
+
+// Последовательное чтение полей из данных кадра
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf }
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StorageError> {
+        if self.buf.len() < len {
+            return Err(StorageError::Protocol("truncated frame".to_string()));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+    fn u8(&mut self) -> Result<u8, StorageError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, StorageError> {
+        Ok(read_u32(self.take(4)?, 0))
+    }
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+    fn bytes(&mut self) -> Result<&'a [u8], StorageError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    fn string(&mut self) -> Result<String, StorageError> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| StorageError::Protocol("invalid utf-8".to_string()))
     }
 }
+
+const ERROR_PERMISSION: u8 = 1;
+const ERROR_IO: u8 = 2;
+const ERROR_PROTOCOL: u8 = 3;
+const ERROR_RATE_LIMITED: u8 = 4;
+const ERROR_CIRCUIT_OPEN: u8 = 5;
+const ERROR_POISONED: u8 = 6;
+// Остальные ошибки передаются текстом и становятся StorageError::Remote
+const ERROR_OTHER: u8 = 7;
+
+fn encode_error(err: &StorageError) -> Vec<u8> {
+    let mut buf = vec![];
+    match err {
+        StorageError::PermissionDenied { user, permission } => {
+            buf.push(ERROR_PERMISSION);
+            put_str(&mut buf, user);
+            buf.push(match permission {
+                Permission::Read => 0,
+                Permission::Write => 1,
+            });
+        }
+        StorageError::Io(err) => {
+            buf.push(ERROR_IO);
+            put_str(&mut buf, &err.to_string());
+        }
+        StorageError::Protocol(msg) => {
+            buf.push(ERROR_PROTOCOL);
+            put_str(&mut buf, msg);
+        }
+        StorageError::RateLimited => buf.push(ERROR_RATE_LIMITED),
+        StorageError::CircuitOpen => buf.push(ERROR_CIRCUIT_OPEN),
+        StorageError::Poisoned => buf.push(ERROR_POISONED),
+        StorageError::Remote(msg) => {
+            buf.push(ERROR_OTHER);
+            put_str(&mut buf, msg);
+        }
+        StorageError::Recorded(_) | StorageError::Replay(_) => {
+            buf.push(ERROR_OTHER);
+            put_str(&mut buf, &err.to_string());
+        }
+    }
+    buf
+}
+
+fn decode_error(payload: &[u8]) -> Result<StorageError, StorageError> {
+    let mut decoder = Decoder::new(payload);
+    Ok(match decoder.u8()? {
+        ERROR_PERMISSION => StorageError::PermissionDenied {
+            user: decoder.string()?,
+            permission: match decoder.u8()? {
+                0 => Permission::Read,
+                _ => Permission::Write,
+            },
+        },
+        ERROR_IO => StorageError::Io(io::Error::other(decoder.string()?)),
+        ERROR_PROTOCOL => StorageError::Protocol(decoder.string()?),
+        ERROR_RATE_LIMITED => StorageError::RateLimited,
+        ERROR_CIRCUIT_OPEN => StorageError::CircuitOpen,
+        ERROR_POISONED => StorageError::Poisoned,
+        ERROR_OTHER => StorageError::Remote(decoder.string()?),
+        kind => {
+            return Err(StorageError::Protocol(format!(
+                "bad error kind {}",
+                kind
+            )))
+        }
+    })
+}
+
+/*
+ * Все, что умеет передать закодированный запрос и получить ответ,
+ * автоматически становится Storage. Так устроены удаленный прокси и прокси
+ * воспроизведения записанных вызовов
+ */
+trait Transport {
+    // Отправляет запрос и возвращает данные успешного ответа
+    fn call(
+        &mut self,
+        kind: u8,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, StorageError>;
+}
+
+struct RemoteProxy {
+    stream: UnixStream,
+    next_id: u32,
+}
+impl RemoteProxy {
+    fn new(stream: UnixStream) -> Self {
+        RemoteProxy { stream, next_id: 0 }
+    }
+    #[allow(unused)]
+    fn connect(path: &Path) -> Result<Self, StorageError> {
+        Ok(RemoteProxy::new(UnixStream::connect(path)?))
+    }
+}
+impl Transport for RemoteProxy {
+    fn call(
+        &mut self,
+        kind: u8,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        self.next_id = self.next_id.wrapping_add(1);
+        write_frame(&mut self.stream, self.next_id, kind, payload)?;
+        let (id, kind, payload) =
+            read_frame(&mut self.stream)?.ok_or_else(|| {
+                StorageError::Protocol("connection closed".to_string())
+            })?;
+        if id != self.next_id {
+            return Err(StorageError::Protocol(format!(
+                "expected response {}, got {}",
+                self.next_id, id
+            )));
+        }
+        match kind {
+            FRAME_OK => Ok(payload),
+            FRAME_ERROR => Err(decode_error(&payload)?),
+            _ => {
+                Err(StorageError::Protocol(format!("bad frame type {}", kind)))
+            }
+        }
+    }
+}
+impl<T: Transport> Storage for T {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        let mut payload = vec![];
+        put_str(&mut payload, key);
+        let response = self.call(FRAME_GET, &payload)?;
+        let mut decoder = Decoder::new(&response);
+        match decoder.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(decoder.string()?)),
+        }
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        let mut payload = vec![];
+        put_str(&mut payload, key);
+        put_str(&mut payload, value);
+        self.call(FRAME_PUT, &payload)?;
+        Ok(())
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        let mut payload = vec![];
+        put_str(&mut payload, key);
+        self.call(FRAME_REMOVE, &payload)?;
+        Ok(())
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        let mut payload = vec![];
+        put_str(&mut payload, prefix);
+        let response = self.call(FRAME_SCAN, &payload)?;
+        let mut decoder = Decoder::new(&response);
+        let count = decoder.u32()?;
+        let mut pairs = vec![];
+        for _ in 0..count {
+            pairs.push((decoder.string()?, decoder.string()?));
+        }
+        Ok(pairs)
+    }
+}
+
+// Выполняет один запрос над хранилищем и возвращает данные ответа
+fn handle_request(
+    storage: &mut dyn Storage,
+    kind: u8,
+    payload: &[u8],
+) -> Result<Vec<u8>, StorageError> {
+    let mut decoder = Decoder::new(payload);
+    let mut response = vec![];
+    match kind {
+        FRAME_GET => match storage.get(&decoder.string()?)? {
+            Some(value) => {
+                response.push(1);
+                put_str(&mut response, &value);
+            }
+            None => response.push(0),
+        },
+        FRAME_PUT => {
+            let key = decoder.string()?;
+            storage.put(&key, &decoder.string()?)?;
+        }
+        FRAME_REMOVE => storage.remove(&decoder.string()?)?,
+        FRAME_SCAN => {
+            let pairs = storage.scan_prefix(&decoder.string()?)?;
+            response.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+            for (key, value) in pairs {
+                put_str(&mut response, &key);
+                put_str(&mut response, &value);
+            }
+        }
+        _ => {
+            return Err(StorageError::Protocol(format!(
+                "unknown request type {}",
+                kind
+            )))
+        }
+    }
+    Ok(response)
+}
+
+// Серверная часть: обслуживает запросы клиента, пока тот не закроет соединение
+fn serve(
+    mut stream: UnixStream,
+    storage: &mut dyn Storage,
+) -> Result<(), StorageError> {
+    while let Some((id, kind, payload)) = read_frame(&mut stream)? {
+        match handle_request(storage, kind, &payload) {
+            Ok(response) => write_frame(&mut stream, id, FRAME_OK, &response)?,
+            Err(err) => {
+                write_frame(&mut stream, id, FRAME_ERROR, &encode_error(&err))?
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Записывающий прокси сохраняет каждый вызов в файл трассы: запрос в том же
+ * кодировании, что и у удаленного прокси, результат и время выполнения.
+ * Запись: [тип u8][запрос][FRAME_OK или FRAME_ERROR][ответ][микросекунды u64],
+ * запрос и ответ записаны как [длина u32][байты]
+ */
+struct RecordingProxy {
+    service: Box<dyn Storage>,
+    trace: File,
+}
+impl RecordingProxy {
+    fn new(
+        service: Box<dyn Storage>,
+        path: &Path,
+    ) -> Result<Self, StorageError> {
+        Ok(RecordingProxy {
+            service,
+            trace: File::create(path)?,
+        })
+    }
+}
+impl Transport for RecordingProxy {
+    fn call(
+        &mut self,
+        kind: u8,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        let start = Instant::now();
+        let result = handle_request(self.service.as_mut(), kind, payload);
+        let elapsed = start.elapsed().as_micros() as u64;
+        let mut record = vec![kind];
+        put_bytes(&mut record, payload);
+        match &result {
+            Ok(response) => {
+                record.push(FRAME_OK);
+                put_bytes(&mut record, response);
+            }
+            Err(err) => {
+                record.push(FRAME_ERROR);
+                put_bytes(&mut record, err.to_string().as_bytes());
+            }
+        }
+        record.extend_from_slice(&elapsed.to_le_bytes());
+        self.trace.write_all(&record)?;
+        result
+    }
+}
+
+struct TraceEntry {
+    kind: u8,
+    request: Vec<u8>,
+    result: Result<Vec<u8>, String>,
+    elapsed: Duration,
+}
+
+/*
+ * Воспроизводящий прокси отвечает на вызовы по записанной трассе, настоящее
+ * хранилище ему не нужно. Вызовы должны идти в том же порядке и с теми же
+ * аргументами, что и при записи, иначе возвращается ошибка Replay
+ */
+struct ReplayProxy {
+    entries: VecDeque<TraceEntry>,
+}
+impl ReplayProxy {
+    fn open(path: &Path) -> Result<Self, StorageError> {
+        let mut buf = vec![];
+        File::open(path)?.read_to_end(&mut buf)?;
+        let mut decoder = Decoder::new(&buf);
+        let mut entries = VecDeque::new();
+        while !decoder.is_empty() {
+            let kind = decoder.u8()?;
+            let request = decoder.bytes()?.to_vec();
+            let status = decoder.u8()?;
+            let response = decoder.bytes()?;
+            let result = match status {
+                FRAME_OK => Ok(response.to_vec()),
+                _ => Err(String::from_utf8_lossy(response).to_string()),
+            };
+            let elapsed =
+                u64::from_le_bytes(decoder.take(8)?.try_into().unwrap());
+            entries.push_back(TraceEntry {
+                kind,
+                request,
+                result,
+                elapsed: Duration::from_micros(elapsed),
+            });
+        }
+        Ok(ReplayProxy { entries })
+    }
+    // Сколько времени занимали оставшиеся вызовы при записи
+    fn recorded_time(&self) -> Duration {
+        self.entries.iter().map(|entry| entry.elapsed).sum()
+    }
+}
+impl Transport for ReplayProxy {
+    fn call(
+        &mut self,
+        kind: u8,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        let entry = self.entries.pop_front().ok_or_else(|| {
+            StorageError::Replay("trace is exhausted".to_string())
+        })?;
+        if entry.kind != kind || entry.request != payload {
+            return Err(StorageError::Replay(format!(
+                "unexpected call of type {}, recorded type {}",
+                kind, entry.kind
+            )));
+        }
+        entry.result.map_err(StorageError::Recorded)
+    }
+}
+
+// Источник времени, чтобы в тестах можно было управлять временем вручную
+trait Clock {
+    fn now(&self) -> Instant;
+}
+
+struct SystemClock {}
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+struct ManualClock {
+    now: Cell<Instant>,
+}
+impl ManualClock {
+    fn new() -> Self {
+        ManualClock {
+            now: Cell::new(Instant::now()),
+        }
+    }
+    fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/*
+ * Прокси ниже только решают, пропускать ли вызов к хранилищу, поэтому все
+ * методы Storage у них сводятся к одному методу guard
+ */
+macro_rules! impl_guarded_storage {
+    ($proxy:ty) => {
+        impl Storage for $proxy {
+            fn get(
+                &mut self,
+                key: &str,
+            ) -> Result<Option<String>, StorageError> {
+                self.guard(|service| service.get(key))
+            }
+            fn put(
+                &mut self,
+                key: &str,
+                value: &str,
+            ) -> Result<(), StorageError> {
+                self.guard(|service| service.put(key, value))
+            }
+            fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+                self.guard(|service| service.remove(key))
+            }
+            fn scan_prefix(
+                &mut self,
+                prefix: &str,
+            ) -> Result<Vec<(String, String)>, StorageError> {
+                self.guard(|service| service.scan_prefix(prefix))
+            }
+        }
+    };
+}
+
+/*
+ * Token bucket: в ведре не больше capacity жетонов, каждый вызов забирает
+ * один, жетоны восстанавливаются со скоростью refill_per_sec
+ */
+struct RateLimitProxy {
+    service: Box<dyn Storage>,
+    clock: Rc<dyn Clock>,
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl RateLimitProxy {
+    fn new(
+        service: Box<dyn Storage>,
+        clock: Rc<dyn Clock>,
+        capacity: u32,
+        refill_per_sec: f64,
+    ) -> Self {
+        let last_refill = clock.now();
+        RateLimitProxy {
+            service,
+            clock,
+            capacity: capacity as f64,
+            refill_per_sec,
+            tokens: capacity as f64,
+            last_refill,
+        }
+    }
+    fn guard<R>(
+        &mut self,
+        call: impl FnOnce(&mut dyn Storage) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return Err(StorageError::RateLimited);
+        }
+        self.tokens -= 1.0;
+        call(self.service.as_mut())
+    }
+}
+impl_guarded_storage!(RateLimitProxy);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CircuitState {
+    // Вызовы проходят, считаем ошибки подряд
+    Closed { failures: u32 },
+    // Вызовы сразу отклоняются до истечения cooldown
+    Open { since: Instant },
+    // Пропускаем пробный вызов: успех закрывает цепь, ошибка снова открывает
+    HalfOpen,
+}
+
+struct CircuitBreakerProxy {
+    service: Box<dyn Storage>,
+    clock: Rc<dyn Clock>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: CircuitState,
+}
+impl CircuitBreakerProxy {
+    fn new(
+        service: Box<dyn Storage>,
+        clock: Rc<dyn Clock>,
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        CircuitBreakerProxy {
+            service,
+            clock,
+            failure_threshold,
+            cooldown,
+            state: CircuitState::Closed { failures: 0 },
+        }
+    }
+    fn state(&self) -> CircuitState {
+        self.state
+    }
+    /*
+     * Отказы бэкенда и соединения с ним. Ошибки прав доступа и т.п. цепь
+     * не размыкают, в том числе пришедшие с удаленного сервера
+     */
+    fn is_failure(err: &StorageError) -> bool {
+        matches!(err, StorageError::Io(_) | StorageError::Protocol(_))
+    }
+    fn guard<R>(
+        &mut self,
+        call: impl FnOnce(&mut dyn Storage) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        let now = self.clock.now();
+        if let CircuitState::Open { since } = self.state {
+            if now.duration_since(since) < self.cooldown {
+                return Err(StorageError::CircuitOpen);
+            }
+            self.state = CircuitState::HalfOpen;
+        }
+        let result = call(self.service.as_mut());
+        match &result {
+            Err(err) if Self::is_failure(err) => {
+                self.state = match self.state {
+                    CircuitState::Closed { failures }
+                        if failures + 1 < self.failure_threshold =>
+                    {
+                        CircuitState::Closed {
+                            failures: failures + 1,
+                        }
+                    }
+                    _ => CircuitState::Open { since: now },
+                };
+            }
+            _ => self.state = CircuitState::Closed { failures: 0 },
+        }
+        result
+    }
+}
+impl_guarded_storage!(CircuitBreakerProxy);
+
+// Нестабильное хранилище для примера: пока down == true, все вызовы падают
+struct FlakyStorage {
+    service: Database,
+    down: Rc<Cell<bool>>,
+}
+impl FlakyStorage {
+    fn check(&self) -> Result<(), StorageError> {
+        if self.down.get() {
+            return Err(
+                io::Error::from(io::ErrorKind::ConnectionRefused).into()
+            );
+        }
+        Ok(())
+    }
+}
+impl Storage for FlakyStorage {
+    fn get(&mut self, key: &str) -> Result<Option<String>, StorageError> {
+        self.check()?;
+        self.service.get(key)
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.check()?;
+        self.service.put(key, value)
+    }
+    fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.check()?;
+        self.service.remove(key)
+    }
+    fn scan_prefix(
+        &mut self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, StorageError> {
+        self.check()?;
+        self.service.scan_prefix(prefix)
+    }
+}
+
 fn main() {
     let service = Box::new(Database::new());
-    let proxy = DatabaseProxy::new(service);
-    proxy.save();
-    proxy.remove();
+    let mut proxy = DatabaseProxy::new(service);
+    proxy.put("key", "value").unwrap();
+    proxy.remove("key").unwrap();
+
+    // Прокси можно вкладывать друг в друга, клиент видит только Storage
+    let lazy = LazyProxy::new(Box::new(|| Box::new(Database::new())));
+    println!("Lazy proxy created, database is not connected yet");
+    let cached = CachingProxy::new(Box::new(lazy));
+    let mut admin = AccessControlProxy::new(
+        Box::new(cached),
+        User::new("admin", &[Permission::Read, Permission::Write]),
+    );
+    admin.put("answer", "42").unwrap();
+    // Оба чтения обслуживаются кешем, до базы запрос не доходит
+    println!("{:?}", admin.get("answer"));
+    println!("{:?}", admin.get("answer"));
+
+    let mut guest = AccessControlProxy::new(
+        Box::new(Database::new()),
+        User::new("guest", &[Permission::Read]),
+    );
+    if let Err(err) = guest.put("answer", "0") {
+        println!("Error: {}", err);
+    }
+
+    let path = std::env::temp_dir().join("proxy_storage.log");
+    let _ = std::fs::remove_file(&path);
+    {
+        let mut db = FileDatabase::open(&path).unwrap();
+        db.put("user:1", "Alice").unwrap();
+        db.put("user:2", "Bob").unwrap();
+        db.put("order:1", "coffe").unwrap();
+        db.remove("user:2").unwrap();
+    }
+    // Имитируем падение посреди записи: в конце файла оборванная запись
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&FileDatabase::encode(OP_PUT, "user:3", "Eve")[..7])
+        .unwrap();
+    let mut db =
+        CachingProxy::new(Box::new(FileDatabase::open(&path).unwrap()));
+    println!("{:?}", db.scan_prefix("user:"));
+    drop(db);
+    // Испорченный байт в середине файла не хвост: открытие сообщает об ошибке
+    let mut buf = std::fs::read(&path).unwrap();
+    buf[HEADER_LEN] ^= 0xff;
+    std::fs::write(&path, &buf).unwrap();
+    if let Err(err) = FileDatabase::open(&path) {
+        println!("Error: {}", err);
+    }
+    let _ = std::fs::remove_file(&path);
+
+    // Сервер с базой работает в отдельном потоке, клиент видит только Storage
+    let (client, server) = UnixStream::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let mut db = AccessControlProxy::new(
+            Box::new(Database::new()),
+            User::new("remote", &[Permission::Read]),
+        );
+        serve(server, &mut db)
+    });
+    // Отказы в доступе на сервере не размыкают цепь на клиенте
+    let mut remote = CircuitBreakerProxy::new(
+        Box::new(RemoteProxy::new(client)),
+        Rc::new(SystemClock {}),
+        3,
+        Duration::from_secs(10),
+    );
+    println!("{:?}", remote.get("answer"));
+    for _ in 0..3 {
+        if let Err(err) = remote.put("answer", "42") {
+            println!("Error: {}", err);
+        }
+    }
+    println!("{:?}", remote.state());
+    drop(remote);
+    handle.join().unwrap().unwrap();
+
+    // Записываем обращения к настоящему хранилищу...
+    let trace = std::env::temp_dir().join("proxy_trace.bin");
+    {
+        let mut recorder = AccessControlProxy::new(
+            Box::new(
+                RecordingProxy::new(Box::new(Database::new()), &trace).unwrap(),
+            ),
+            User::new("tester", &[Permission::Read, Permission::Write]),
+        );
+        recorder.put("user:1", "Alice").unwrap();
+        println!("{:?}", recorder.get("user:1"));
+        println!("{:?}", recorder.scan_prefix("user:"));
+    }
+    // ...и воспроизводим их без базы данных
+    let mut replay = ReplayProxy::open(&trace).unwrap();
+    println!("Recorded time: {:?}", replay.recorded_time());
+    replay.put("user:1", "Alice").unwrap();
+    println!("{:?}", replay.get("user:1"));
+    println!("{:?}", replay.scan_prefix("user:"));
+    // Вызова, которого не было при записи, в трассе нет
+    println!("{:?}", replay.get("user:2"));
+    let _ = std::fs::remove_file(&trace);
+
+    // Не больше 2 вызовов подряд, затем 1 вызов в секунду
+    let mut limited = RateLimitProxy::new(
+        Box::new(Database::new()),
+        Rc::new(SystemClock {}),
+        2,
+        1.0,
+    );
+    for _ in 0..3 {
+        println!("{:?}", limited.put("key", "value"));
+    }
+
+    let clock = Rc::new(ManualClock::new());
+    let down = Rc::new(Cell::new(true));
+    let flaky = FlakyStorage {
+        service: Database::new(),
+        down: down.clone(),
+    };
+    let mut breaker = CircuitBreakerProxy::new(
+        Box::new(flaky),
+        clock.clone(),
+        3,
+        Duration::from_secs(10),
+    );
+    for _ in 0..4 {
+        let result = breaker.put("key", "value");
+        println!(
+            "{:?} -> {:?}",
+            result.map_err(|e| e.to_string()),
+            breaker.state()
+        );
+    }
+    // После паузы цепь пропускает пробный вызов, бэкенд уже восстановился
+    clock.advance(Duration::from_secs(10));
+    down.set(false);
+    println!("{:?} -> {:?}", breaker.put("key", "value"), breaker.state());
 }
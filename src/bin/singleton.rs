@@ -9,40 +9,171 @@
  * - Когда вам хочется иметь больше контроля над глобальными переменными
 **/
 use std::{
-    collections::HashMap,
-    sync::{Mutex, OnceLock},
+    collections::{hash_map::Entry, HashMap},
+    hash::Hash,
+    sync::{LockResult, PoisonError, RwLock},
+    thread,
 };
 
+/*
+ * Блокировка отравляется, если поток запаниковал, держа ее. Данные при этом
+ * остаются в том состоянии, в котором их оставил упавший поток, и мы
+ * продолжаем работать с ними, а не паникуем во всех остальных потоках
+ */
+fn recover<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+/*
+ * Обобщенный одиночка для static. Значение создается конструктором при первом
+ * обращении. Доступ идет через RwLock: читать могут многие потоки сразу,
+ * писать только один. Ссылки наружу не отдаются, работа с значением только
+ * внутри замыкания, поэтому блокировка не может утечь
+ */
+pub struct Singleton<T> {
+    init: fn() -> T,
+    value: RwLock<Option<T>>,
+}
+impl<T> Singleton<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Singleton {
+            init,
+            value: RwLock::new(None),
+        }
+    }
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        {
+            let value = recover(self.value.read());
+            if let Some(value) = value.as_ref() {
+                return f(value);
+            }
+        }
+        // Значения еще нет: создаем его под блокировкой на запись
+        self.write(|value| f(value))
+    }
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut value = recover(self.value.write());
+        f(value.get_or_insert_with(self.init))
+    }
+    /*
+     * Только для тестов: забывает значение, и следующее обращение снова
+     * вызовет конструктор. Так глобальное состояние не перетекает между тестами.
+     * В обычной сборке метода нет, и рабочий код не может стереть состояние
+     */
+    #[cfg(test)]
+    pub fn reset(&self) {
+        *recover(self.value.write()) = None;
+        self.value.clear_poison();
+    }
+}
+
+/*
+ * Словарь синхронизирован сам, поэтому из одиночки его достаточно брать
+ * через read. Наружу отдаются только копии значений, блокировка никогда
+ * не покидает методы словаря
+ */
+static DICTIONARY: Singleton<Dictionary<i32, i32>> =
+    Singleton::new(Dictionary::new);
+
 // Структура будет в модуле, поэтому напрямую создать ее нельзя, так как поля не публичные
-pub struct Dictionary {
-    data: HashMap<i32, i32>,
+pub struct Dictionary<K, V> {
+    data: RwLock<HashMap<K, V>>,
 }
-impl Dictionary {
+impl<K: Hash + Eq, V> Dictionary<K, V> {
     // Конструктор также будет скрыт в не модуля
     fn new() -> Self {
+        println!("Dictionary created");
         Dictionary {
             data: Default::default(),
         }
     }
-    pub fn insert(&mut self, key: i32, value: i32) {
-        self.data.insert(key, value);
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        recover(self.data.write()).insert(key, value)
     }
-    pub fn get(&self, key: &i32) -> Option<&i32> {
-        self.data.get(key)
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        recover(self.data.read()).get(key).cloned()
     }
-    pub fn get_instance() -> &'static Mutex<Dictionary> {
-        static INSTANCE: OnceLock<Mutex<Dictionary>> = OnceLock::new();
-        INSTANCE.get_or_init(|| Mutex::new(Dictionary::new()))
+    pub fn remove(&self, key: &K) -> Option<V> {
+        recover(self.data.write()).remove(key)
     }
-}
-fn main() {
+    pub fn len(&self) -> usize {
+        recover(self.data.read()).len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // Атомарная работа с записью: словарь заблокирован, пока выполняется f
+    pub fn entry<R>(&self, key: K, f: impl FnOnce(Entry<K, V>) -> R) -> R {
+        f(recover(self.data.write()).entry(key))
+    }
+    // Изменяет значение, создавая его через default, если ключа еще нет
+    pub fn update_with<R>(
+        &self,
+        key: K,
+        default: impl FnOnce() -> V,
+        update: impl FnOnce(&mut V) -> R,
+    ) -> R {
+        self.entry(key, |entry| update(entry.or_insert_with(default)))
+    }
+    // Копия содержимого на момент вызова, по ней можно спокойно итерироваться
+    pub fn snapshot(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
     {
-        let mut dict = Dictionary::get_instance().lock().unwrap();
-        dict.insert(1, 1);
+        recover(self.data.read())
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
     }
+}
+impl Dictionary<i32, i32> {
+    pub fn get_instance() -> &'static Singleton<Dictionary<i32, i32>> {
+        &DICTIONARY
+    }
+}
+fn main() {
+    Dictionary::get_instance().read(|dict| dict.insert(1, 1));
     for _ in 0..10 {
         // Каждый раз мы получаем один и тот же объект
-        let dict = Dictionary::get_instance().lock().unwrap();
-        println!("{:?}", dict.get(&1));
+        let value = Dictionary::get_instance().read(|dict| dict.get(&1));
+        println!("{:?}", value);
+    }
+    for key in 2..5 {
+        Dictionary::get_instance()
+            .read(|dict| dict.update_with(key, || 0, |value| *value += key));
+    }
+    Dictionary::get_instance().read(|dict| dict.remove(&3));
+    let mut snapshot = Dictionary::get_instance().read(|dict| dict.snapshot());
+    snapshot.sort();
+    println!("{:?}", snapshot);
+
+    // Поток паникует посреди изменения, но словарь остается рабочим
+    let _ = thread::spawn(|| {
+        Dictionary::get_instance()
+            .read(|dict| dict.update_with(1, || 0, |_| panic!("worker failed")))
+    })
+    .join();
+    let value = Dictionary::get_instance().read(|dict| dict.get(&1));
+    println!("After panic: {:?}", value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Свой экземпляр, чтобы не зависеть от порядка тестов над DICTIONARY
+    static COUNTERS: Singleton<Dictionary<&str, u32>> =
+        Singleton::new(Dictionary::new);
+
+    #[test]
+    fn reset_forgets_value() {
+        COUNTERS.read(|dict| dict.insert("runs", 1));
+        assert_eq!(COUNTERS.read(|dict| dict.get(&"runs")), Some(1));
+        COUNTERS.reset();
+        assert_eq!(COUNTERS.read(|dict| dict.get(&"runs")), None);
     }
 }
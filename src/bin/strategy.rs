@@ -10,68 +10,386 @@
  * - когда в классе определено много поведений, что представлено большим
  *   количество условных операторов
  **/
+use std::cmp::Ordering;
 
-trait Sorting {
-    fn sort(&self, data: &mut DataSet);
+trait Sorting<T> {
+    fn name(&self) -> &'static str;
+    fn sort(&self, data: &mut [T]);
 }
 
-struct FastSorting {}
+// Вставками: быстрее всех на крошечных и почти упорядоченных данных, устойчива
+struct InsertionSorting {}
 
-impl FastSorting {
-    fn new() -> Self {
-        FastSorting {}
+impl<T: Ord> Sorting<T> for InsertionSorting {
+    fn name(&self) -> &'static str {
+        "insertion sort"
+    }
+    fn sort(&self, data: &mut [T]) {
+        insertion_sort(data);
     }
 }
 
-impl Sorting for FastSorting {
-    fn sort(&self, obj: &mut DataSet) {
-        println!("Used fast sorting");
-        obj.data.sort();
+fn insertion_sort<T: Ord>(data: &mut [T]) {
+    for i in 1..data.len() {
+        let mut j = i;
+        while j > 0 && data[j - 1] > data[j] {
+            data.swap(j - 1, j);
+            j -= 1;
+        }
     }
 }
 
-struct RegularSorting {}
+// Пирамидальная: O(n log n) в худшем случае и без дополнительной памяти
+struct HeapSorting {}
 
-impl RegularSorting {
-    #[allow(unused)]
-    fn new() -> Self {
-        RegularSorting {}
+impl<T: Ord> Sorting<T> for HeapSorting {
+    fn name(&self) -> &'static str {
+        "heapsort"
+    }
+    fn sort(&self, data: &mut [T]) {
+        heap_sort(data);
+    }
+}
+
+fn heap_sort<T: Ord>(data: &mut [T]) {
+    for root in (0..data.len() / 2).rev() {
+        sift_down(data, root);
+    }
+    // Максимум уходит в конец, куча сокращается на один элемент
+    for end in (1..data.len()).rev() {
+        data.swap(0, end);
+        sift_down(&mut data[..end], 0);
+    }
+}
+
+fn sift_down<T: Ord>(heap: &mut [T], mut root: usize) {
+    loop {
+        let mut child = 2 * root + 1;
+        if child >= heap.len() {
+            return;
+        }
+        if child + 1 < heap.len() && heap[child + 1] > heap[child] {
+            child += 1;
+        }
+        if heap[root] >= heap[child] {
+            return;
+        }
+        heap.swap(root, child);
+        root = child;
+    }
+}
+
+// Слиянием: устойчива, равные элементы сохраняют исходный порядок
+struct MergeSorting {}
+
+impl<T: Ord + Clone> Sorting<T> for MergeSorting {
+    fn name(&self) -> &'static str {
+        "merge sort"
+    }
+    fn sort(&self, data: &mut [T]) {
+        merge_sort(data);
+    }
+}
+
+fn merge_sort<T: Ord + Clone>(data: &mut [T]) {
+    if data.len() <= 1 {
+        return;
+    }
+    let mid = data.len() / 2;
+    merge_sort(&mut data[..mid]);
+    merge_sort(&mut data[mid..]);
+    // Половины уже стоят по порядку, на упорядоченных данных это O(n)
+    if data[mid - 1] <= data[mid] {
+        return;
+    }
+    /*
+     * Копируется только левая половина. Запись всегда идет левее еще не
+     * прочитанных элементов правой половины, поэтому они не затираются
+     */
+    let left = data[..mid].to_vec();
+    let (mut i, mut j, mut k) = (0, mid, 0);
+    while i < left.len() && j < data.len() {
+        // При равенстве берем из левой половины, отсюда устойчивость
+        if data[j] < left[i] {
+            data[k] = data[j].clone();
+            j += 1;
+        } else {
+            data[k] = left[i].clone();
+            i += 1;
+        }
+        k += 1;
+    }
+    for item in &left[i..] {
+        data[k] = item.clone();
+        k += 1;
+    }
+}
+
+// Целые числа, которые можно сортировать поразрядно
+trait RadixKey: Ord + Copy {
+    // Ключ, у которого порядок беззнаковых чисел совпадает с порядком значений
+    fn key(self) -> u64;
+}
+
+macro_rules! impl_radix_key {
+    (unsigned: $($unsigned:ty),*; signed: $($signed:ty),*) => {
+        $(impl RadixKey for $unsigned {
+            fn key(self) -> u64 {
+                self as u64
+            }
+        })*
+        // Инверсия знакового бита ставит отрицательные числа перед положительными
+        $(impl RadixKey for $signed {
+            fn key(self) -> u64 {
+                (self as i64 as u64) ^ (1 << 63)
+            }
+        })*
+    };
+}
+impl_radix_key!(unsigned: u8, u16, u32, u64, usize; signed: i8, i16, i32, i64, isize);
+
+// Поразрядная: O(n) на байт ключа без сравнений, только для целых чисел
+struct RadixSorting {}
+
+impl<T: RadixKey> Sorting<T> for RadixSorting {
+    fn name(&self) -> &'static str {
+        "radix sort"
+    }
+    fn sort(&self, data: &mut [T]) {
+        radix_sort(data);
+    }
+}
+
+fn radix_sort<T: RadixKey>(data: &mut [T]) {
+    let mut buffer = data.to_vec();
+    for shift in (0..64).step_by(8) {
+        let digit = |item: &T| (item.key() >> shift) as u8 as usize;
+        let mut counts = [0usize; 256];
+        for item in data.iter() {
+            counts[digit(item)] += 1;
+        }
+        // Все элементы с одинаковым разрядом, проход ничего не изменит
+        if counts.contains(&data.len()) {
+            continue;
+        }
+        let mut offset = 0;
+        for count in counts.iter_mut() {
+            let start = offset;
+            offset += *count;
+            *count = start;
+        }
+        for item in data.iter() {
+            let slot = &mut counts[digit(item)];
+            buffer[*slot] = *item;
+            *slot += 1;
+        }
+        data.copy_from_slice(&buffer);
     }
 }
 
-impl Sorting for RegularSorting {
-    fn sort(&self, obj: &mut DataSet) {
-        println!("Used regular sorting, but less memory is used");
-        obj.data.sort()
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    Insertion,
+    Merge,
+    Heap,
+    Radix,
+}
+
+/*
+ * Выбирает алгоритм по данным: размеру и степени упорядоченности.
+ * Поразрядная сортировка доступна только для целых чисел, поэтому она
+ * подключается отдельным конструктором
+ */
+struct AutoSorting<T> {
+    radix: Option<fn(&mut [T])>,
+    low_memory: bool,
+}
+
+impl<T: Ord + Clone> AutoSorting<T> {
+    fn new() -> Self {
+        AutoSorting {
+            radix: None,
+            low_memory: false,
+        }
+    }
+    /*
+     * Сортировка на месте вместо слияния и поразрядной сортировки:
+     * обеим нужен дополнительный буфер
+     */
+    fn low_memory(mut self) -> Self {
+        self.low_memory = true;
+        self
+    }
+    fn choose(&self, data: &[T]) -> Algorithm {
+        if data.len() <= 16 {
+            return Algorithm::Insertion;
+        }
+        // Сколько раз соседние элементы стоят не по порядку
+        let descents = data.windows(2).filter(|pair| pair[0] > pair[1]).count();
+        if descents == 0 {
+            Algorithm::Insertion
+        } else if self.low_memory {
+            Algorithm::Heap
+        } else if descents * 32 < data.len() {
+            Algorithm::Merge
+        } else if self.radix.is_some() && data.len() >= 256 {
+            Algorithm::Radix
+        } else {
+            Algorithm::Merge
+        }
     }
 }
 
-struct DataSet {
-    data: Vec<i32>,
-    sorting: Option<Box<dyn Sorting>>,
+impl<T: RadixKey> AutoSorting<T> {
+    fn integers() -> Self {
+        AutoSorting {
+            radix: Some(radix_sort::<T>),
+            low_memory: false,
+        }
+    }
 }
 
-impl DataSet {
-    fn new(data: Vec<i32>, sorting: Box<dyn Sorting>) -> Self {
-        DataSet {
-            data,
-            sorting: Some(sorting),
+impl<T: Ord + Clone> Sorting<T> for AutoSorting<T> {
+    fn name(&self) -> &'static str {
+        "auto sorting"
+    }
+    fn sort(&self, data: &mut [T]) {
+        let algorithm = self.choose(data);
+        println!(
+            "Auto sorting chose {:?} for {} items",
+            algorithm,
+            data.len()
+        );
+        match (algorithm, self.radix) {
+            (Algorithm::Insertion, _) => insertion_sort(data),
+            (Algorithm::Merge, _) => merge_sort(data),
+            (Algorithm::Heap, _) => heap_sort(data),
+            (Algorithm::Radix, Some(radix)) => radix(data),
+            (Algorithm::Radix, None) => {
+                unreachable!("radix is chosen only when set")
+            }
         }
     }
+}
+
+struct DataSet<T: Ord> {
+    data: Vec<T>,
+    sorting: Box<dyn Sorting<T>>,
+}
+
+impl<T: Ord> DataSet<T> {
+    fn new(data: Vec<T>, sorting: Box<dyn Sorting<T>>) -> Self {
+        DataSet { data, sorting }
+    }
     fn sort(&mut self) {
-        let sorting = self.sorting.take().unwrap();
-        sorting.sort(self);
-        self.sorting = Some(sorting);
+        println!("Used {}", self.sorting.name());
+        self.sorting.sort(&mut self.data);
     }
-    #[allow(unused)]
-    fn set_sorting(&mut self, sorting: Box<dyn Sorting>) {
-        self.sorting = Some(sorting);
+    fn set_sorting(&mut self, sorting: Box<dyn Sorting<T>>) {
+        self.sorting = sorting;
     }
 }
 
+/*
+ * Сотрудник сравнивается только по возрасту, имя в сравнении не участвует.
+ * Равенство должно согласовываться с порядком, поэтому ровесники равны,
+ * даже если их зовут по-разному
+ */
+#[derive(Debug, Clone)]
+struct Employee {
+    name: &'static str,
+    age: u32,
+}
+
+impl PartialEq for Employee {
+    fn eq(&self, other: &Self) -> bool {
+        self.age == other.age
+    }
+}
+
+impl Eq for Employee {}
+
+impl Ord for Employee {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.age.cmp(&other.age)
+    }
+}
+
+impl PartialOrd for Employee {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Простой генератор псевдослучайных чисел, чтобы пример был воспроизводимым
+fn next_number(seed: &mut u32) -> i32 {
+    *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+    (*seed >> 8) as i32 - (1 << 23)
+}
+
 fn main() {
     let mut data_set =
-        DataSet::new(vec![4, 67, 2, 7, 5], Box::new(FastSorting::new()));
+        DataSet::new(vec![4, 67, 2, 7, 5], Box::new(InsertionSorting {}));
     data_set.sort();
     println!("{:?}", data_set.data);
+
+    // Стратегию меняем на лету, результат у всех одинаковый
+    let mut seed = 42;
+    let numbers: Vec<i32> = (0..1000).map(|_| next_number(&mut seed)).collect();
+    let mut expected = numbers.clone();
+    expected.sort();
+    let strategies: Vec<Box<dyn Sorting<i32>>> = vec![
+        Box::new(InsertionSorting {}),
+        Box::new(HeapSorting {}),
+        Box::new(MergeSorting {}),
+        Box::new(RadixSorting {}),
+        Box::new(AutoSorting::integers()),
+    ];
+    let mut data_set = DataSet::new(vec![], Box::new(HeapSorting {}));
+    for sorting in strategies {
+        data_set.data = numbers.clone();
+        data_set.set_sorting(sorting);
+        data_set.sort();
+        println!("Sorted correctly: {}", data_set.data == expected);
+    }
+
+    // Автоматический выбор зависит от данных
+    let mut nearly_sorted = expected.clone();
+    nearly_sorted.swap(10, 20);
+    let inputs = [vec![3, 1, 2], nearly_sorted, numbers.clone()];
+    for input in inputs {
+        for auto in [
+            AutoSorting::new(),
+            AutoSorting::new().low_memory(),
+            AutoSorting::integers(),
+            AutoSorting::integers().low_memory(),
+        ] {
+            let mut data = input.clone();
+            auto.sort(&mut data);
+        }
+    }
+
+    // Устойчивость: ровесники остаются в исходном порядке
+    let employees = vec![
+        Employee {
+            name: "Anna",
+            age: 30,
+        },
+        Employee {
+            name: "Boris",
+            age: 25,
+        },
+        Employee {
+            name: "Vera",
+            age: 30,
+        },
+        Employee {
+            name: "Gleb",
+            age: 25,
+        },
+    ];
+    let mut data_set = DataSet::new(employees, Box::new(MergeSorting {}));
+    data_set.sort();
+    let names: Vec<_> = data_set.data.iter().map(|e| e.name).collect();
+    println!("{:?}", names);
 }